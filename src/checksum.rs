@@ -0,0 +1,178 @@
+use byteordered::{ByteOrdered, Endianness};
+
+/// Checksum algorithms used by ECU ROMs.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+    /// Wrapping sum of the 32-bit words in the region is stored at the checksum offset.
+    /// The checksum must lie outside of the region.
+    Sum32,
+
+    /// Wrapping sum of the 32-bit words in the region, including the checksum
+    /// itself, equals the argument. The checksum must lie inside of the region.
+    Sum32Target(u32),
+}
+
+/// Describes a checksummed region of a ROM.
+#[derive(Debug, Clone)]
+pub struct ChecksumRegion {
+    /// Printable name of the region
+    pub name: String,
+
+    /// Offset of the first byte of the region
+    pub start: u64,
+
+    /// Offset one past the last byte of the region. The region length must be
+    /// a multiple of 4.
+    pub end: u64,
+
+    /// Offset of the stored 32-bit checksum
+    pub checksum_offset: u64,
+
+    pub algorithm: ChecksumAlgorithm,
+
+    pub endianness: Endianness,
+}
+
+impl ChecksumRegion {
+    /// Returns the checksum stored in `data`. `data` must contain the checksum offset.
+    pub fn stored(&self, data: &[u8]) -> u32 {
+        let offset = self.checksum_offset as usize;
+        read_u32(self.endianness, &data[offset..offset + 4])
+    }
+
+    /// Returns true if the region does not end before it starts and its
+    /// length is a multiple of 4.
+    pub fn is_aligned(&self) -> bool {
+        self.end >= self.start && (self.end - self.start) % 4 == 0
+    }
+
+    /// Returns true if the checksum offset is placed as the algorithm
+    /// requires: outside of the region for [`ChecksumAlgorithm::Sum32`], and
+    /// on a word inside of the region for [`ChecksumAlgorithm::Sum32Target`].
+    pub fn is_checksum_placed(&self) -> bool {
+        let offset = self.checksum_offset;
+        match self.algorithm {
+            ChecksumAlgorithm::Sum32 => {
+                offset.saturating_add(4) <= self.start || offset >= self.end
+            }
+            ChecksumAlgorithm::Sum32Target(_) => {
+                offset >= self.start
+                    && offset.saturating_add(4) <= self.end
+                    && (offset - self.start) % 4 == 0
+            }
+        }
+    }
+
+    /// Computes the checksum that should be stored for the region. `data` is
+    /// the entire ROM and must contain the region and the checksum offset.
+    ///
+    /// # Panics
+    /// Panics if the region is not [aligned](Self::is_aligned) or the checksum
+    /// is not [placed](Self::is_checksum_placed) correctly.
+    pub fn compute(&self, data: &[u8]) -> u32 {
+        assert!(self.is_aligned(), "unaligned checksum region");
+        assert!(self.is_checksum_placed(), "misplaced checksum");
+        let region = &data[self.start as usize..self.end as usize];
+        let sum = region.chunks_exact(4).fold(0_u32, |sum, word| {
+            sum.wrapping_add(read_u32(self.endianness, word))
+        });
+
+        match self.algorithm {
+            ChecksumAlgorithm::Sum32 => sum,
+            ChecksumAlgorithm::Sum32Target(target) => {
+                // Exclude the stored checksum from the sum
+                target.wrapping_sub(sum.wrapping_sub(self.stored(data)))
+            }
+        }
+    }
+
+    /// Writes `checksum` to the checksum offset in `data`.
+    pub fn store(&self, data: &mut [u8], checksum: u32) {
+        let offset = self.checksum_offset as usize;
+        let mut wr = ByteOrdered::runtime(&mut data[offset..offset + 4], self.endianness);
+        // Writing 4 bytes to a 4 byte slice can not fail
+        wr.write_u32(checksum).unwrap();
+    }
+}
+
+/// Result of verifying a single [`ChecksumRegion`].
+#[derive(Debug, Clone)]
+pub struct ChecksumResult {
+    pub region: ChecksumRegion,
+
+    /// Checksum stored in the ROM
+    pub stored: u32,
+
+    /// Checksum computed from the region
+    pub computed: u32,
+}
+
+impl ChecksumResult {
+    /// Returns true if the stored checksum matches the computed checksum.
+    pub fn is_valid(&self) -> bool {
+        self.stored == self.computed
+    }
+}
+
+fn read_u32(endianness: Endianness, bytes: &[u8]) -> u32 {
+    let mut rd = ByteOrdered::runtime(bytes, endianness);
+    // Callers always pass 4 bytes
+    rd.read_u32().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(algorithm: ChecksumAlgorithm, checksum_offset: u64) -> ChecksumRegion {
+        ChecksumRegion {
+            name: "".to_string(),
+            start: 0,
+            end: 12,
+            checksum_offset,
+            algorithm,
+            endianness: Endianness::Big,
+        }
+    }
+
+    #[test]
+    fn sum32() {
+        let mut data = vec![0, 0, 0, 1, 0, 0, 0, 2, 0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 0];
+        let region = region(ChecksumAlgorithm::Sum32, 12);
+        assert_eq!(region.compute(&data), 2);
+        assert_eq!(region.stored(&data), 0);
+        region.store(&mut data, 2);
+        assert_eq!(&data[12..], &[0, 0, 0, 2]);
+    }
+
+    #[test]
+    fn sum32_target() {
+        let mut data = vec![0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 0];
+        let region = region(ChecksumAlgorithm::Sum32Target(0x5AA5_A55A), 8);
+        let checksum = region.compute(&data);
+        assert_eq!(checksum, 0x5AA5_A557);
+        region.store(&mut data, checksum);
+        // The stored checksum does not affect the computed value
+        assert_eq!(region.compute(&data), checksum);
+    }
+
+    #[test]
+    fn checksum_placement() {
+        assert!(region(ChecksumAlgorithm::Sum32, 12).is_checksum_placed());
+        let after = ChecksumRegion {
+            start: 4,
+            end: 16,
+            ..region(ChecksumAlgorithm::Sum32, 0)
+        };
+        assert!(after.is_checksum_placed());
+        assert!(!region(ChecksumAlgorithm::Sum32, 8).is_checksum_placed());
+        assert!(!region(ChecksumAlgorithm::Sum32, 10).is_checksum_placed());
+
+        let target = ChecksumAlgorithm::Sum32Target(0);
+        assert!(region(target, 0).is_checksum_placed());
+        assert!(region(target, 8).is_checksum_placed());
+        assert!(!region(target, 12).is_checksum_placed());
+        assert!(!region(target, 10).is_checksum_placed());
+        assert!(!region(target, 6).is_checksum_placed());
+    }
+}
@@ -1,33 +1,20 @@
-use std::ffi;
+use std::collections::VecDeque;
 use std::fmt;
 use std::io;
-use std::io::{Read, Write};
-use std::iter;
-use std::mem;
-use std::result::Result;
+use std::sync::{Arc, Condvar, Mutex, Weak};
 use std::time;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-#[cfg(unix)]
-use socketcan::{CANError, CANFrame, CANSocket};
+#[cfg(feature = "socketcan-datalink")]
+use socketcan::{CANFrame, CANSocket};
 
-#[derive(Debug)]
+#[derive(Debug, Default, Clone)]
 pub struct Message {
     pub id: u32,
     pub data: [u8; 8],
     pub len: u8,
 }
 
-impl Default for Message {
-    fn default() -> Message {
-        Message {
-            id: 0,
-            data: [0; 8],
-            len: 0,
-        }
-    }
-}
-
 impl fmt::Display for Message {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
@@ -66,7 +53,7 @@ pub trait Can {
     fn read(&self, timeout: time::Duration) -> std::io::Result<Message>;
 }
 
-#[cfg(unix)]
+#[cfg(feature = "socketcan-datalink")]
 impl Can for CANSocket {
     fn write(&self, id: u32, message: &[u8]) -> std::io::Result<()> {
         let frame = CANFrame::new(id, message, false, false).unwrap();
@@ -87,6 +74,104 @@ impl Can for CANSocket {
     }
 }
 
+/// Receive queue of a [`VirtualCan`] endpoint.
+#[derive(Default)]
+struct Queue {
+    messages: Mutex<VecDeque<Message>>,
+    available: Condvar,
+}
+
+/// In-memory CAN bus. Messages written by an endpoint are received by every
+/// other endpoint connected to the bus.
+/// # Example
+/// ```
+/// use std::time::Duration;
+/// use overboost::datalink::can::{Can, VirtualBus};
+///
+/// let bus = VirtualBus::new();
+/// let a = bus.endpoint();
+/// let b = bus.endpoint();
+/// a.write(0x7E0, &[0x3E, 0x00]).unwrap();
+/// let msg = b.read(Duration::from_millis(10)).unwrap();
+/// assert_eq!((msg.id, msg.len), (0x7E0, 2));
+/// ```
+#[derive(Clone, Default)]
+pub struct VirtualBus {
+    endpoints: Arc<Mutex<Vec<Weak<Queue>>>>,
+}
+
+impl VirtualBus {
+    pub fn new() -> VirtualBus {
+        VirtualBus::default()
+    }
+
+    /// Connects a new endpoint to the bus. The endpoint only receives
+    /// messages written after it was connected.
+    pub fn endpoint(&self) -> VirtualCan {
+        let queue = Arc::new(Queue::default());
+        self.endpoints.lock().unwrap().push(Arc::downgrade(&queue));
+        VirtualCan {
+            bus: self.clone(),
+            queue,
+        }
+    }
+}
+
+/// Endpoint connected to a [`VirtualBus`].
+pub struct VirtualCan {
+    bus: VirtualBus,
+    queue: Arc<Queue>,
+}
+
+impl Can for VirtualCan {
+    fn write(&self, id: u32, message: &[u8]) -> io::Result<()> {
+        if message.len() > 8 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "CAN message data is larger than 8 bytes",
+            ));
+        }
+        let mut msg = Message {
+            id,
+            len: message.len() as u8,
+            ..Default::default()
+        };
+        msg.data[..message.len()].copy_from_slice(message);
+
+        let mut endpoints = self.bus.endpoints.lock().unwrap();
+        // Drop endpoints that no longer exist
+        endpoints.retain(|endpoint| endpoint.strong_count() > 0);
+        for queue in endpoints.iter().filter_map(Weak::upgrade) {
+            if Arc::ptr_eq(&queue, &self.queue) {
+                continue;
+            }
+            queue.messages.lock().unwrap().push_back(msg.clone());
+            queue.available.notify_all();
+        }
+        Ok(())
+    }
+
+    fn read(&self, timeout: Duration) -> io::Result<Message> {
+        let deadline = Instant::now() + timeout;
+        let mut messages = self.queue.messages.lock().unwrap();
+        loop {
+            if let Some(msg) = messages.pop_front() {
+                return Ok(msg);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::from(io::ErrorKind::TimedOut));
+            }
+            messages = self
+                .queue
+                .available
+                .wait_timeout(messages, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+}
+
 /*
 pub struct J2534Can {
     channel: j2534::Channel,
@@ -214,3 +299,50 @@ impl CanInterface for SocketCan {
         })
     }
 }*/
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn virtual_bus() {
+        let bus = VirtualBus::new();
+        let a = bus.endpoint();
+        let b = bus.endpoint();
+        let c = bus.endpoint();
+
+        a.write(0x123, &[1, 2, 3]).unwrap();
+        for endpoint in &[&b, &c] {
+            let msg = endpoint.read(Duration::from_millis(10)).unwrap();
+            assert_eq!(msg.id, 0x123);
+            assert_eq!(&msg.data[..msg.len as usize], &[1, 2, 3]);
+        }
+
+        // The sender does not receive its own message
+        let err = a.read(Duration::from_millis(10)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        assert!(a.write(0x123, &[0; 9]).is_err());
+    }
+
+    #[test]
+    fn virtual_bus_timeout() {
+        let bus = VirtualBus::new();
+        let a = bus.endpoint();
+        let b = bus.endpoint();
+
+        let start = Instant::now();
+        assert!(b.read(Duration::from_millis(50)).is_err());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            a.write(0x7E8, &[0x7E, 0x00]).unwrap();
+        });
+        let msg = b.read(Duration::from_secs(5)).unwrap();
+        assert_eq!(msg.id, 0x7E8);
+        writer.join().unwrap();
+    }
+}
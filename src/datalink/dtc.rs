@@ -0,0 +1,147 @@
+use std::fmt;
+
+/// groupOfDTC that selects all DTCs in ClearDiagnosticInformation
+pub const DTC_GROUP_ALL: u32 = 0xFF_FFFF;
+
+/// Decoded DTC status byte (statusOfDTC).
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct DtcStatus {
+    /// Bit 0: the most recent test result failed
+    pub test_failed: bool,
+
+    /// Bit 1: a test failed during the current operation cycle
+    pub test_failed_this_operation_cycle: bool,
+
+    /// Bit 2: a test failed during the current or last operation cycle
+    pub pending: bool,
+
+    /// Bit 3: the fault was detected often enough to be stored
+    pub confirmed: bool,
+
+    /// Bit 4: no test completed since the DTCs were cleared
+    pub test_not_completed_since_last_clear: bool,
+
+    /// Bit 5: a test failed since the DTCs were cleared
+    pub test_failed_since_last_clear: bool,
+
+    /// Bit 6: no test completed during the current operation cycle
+    pub test_not_completed_this_operation_cycle: bool,
+
+    /// Bit 7: the ECU requests the warning indicator (MIL)
+    pub warning_indicator_requested: bool,
+}
+
+impl From<u8> for DtcStatus {
+    fn from(status: u8) -> DtcStatus {
+        let bit = |n: u8| status & (1 << n) != 0;
+        DtcStatus {
+            test_failed: bit(0),
+            test_failed_this_operation_cycle: bit(1),
+            pending: bit(2),
+            confirmed: bit(3),
+            test_not_completed_since_last_clear: bit(4),
+            test_failed_since_last_clear: bit(5),
+            test_not_completed_this_operation_cycle: bit(6),
+            warning_indicator_requested: bit(7),
+        }
+    }
+}
+
+impl From<DtcStatus> for u8 {
+    fn from(status: DtcStatus) -> u8 {
+        [
+            status.test_failed,
+            status.test_failed_this_operation_cycle,
+            status.pending,
+            status.confirmed,
+            status.test_not_completed_since_last_clear,
+            status.test_failed_since_last_clear,
+            status.test_not_completed_this_operation_cycle,
+            status.warning_indicator_requested,
+        ]
+        .iter()
+        .enumerate()
+        .fold(0, |byte, (n, &set)| byte | ((set as u8) << n))
+    }
+}
+
+/// Diagnostic trouble code and its status.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Dtc {
+    /// 3-byte DTC. The upper two bytes are the SAE J2012 code, the lowest
+    /// byte is the failure type.
+    pub code: u32,
+
+    pub status: DtcStatus,
+}
+
+impl Dtc {
+    /// Parses a 3-byte DTC followed by its status byte.
+    pub(crate) fn parse(record: &[u8]) -> Dtc {
+        Dtc {
+            code: dtc_code(&record[..3]),
+            status: DtcStatus::from(record[3]),
+        }
+    }
+}
+
+impl fmt::Display for Dtc {
+    /// Formats the code as a SAE J2012 DTC followed by the failure type,
+    /// e.g. `P0301-00`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let system = ['P', 'C', 'B', 'U'][(self.code >> 22) as usize & 0x03];
+        write!(
+            f,
+            "{}{:04X}-{:02X}",
+            system,
+            (self.code >> 8) & 0x3FFF,
+            self.code & 0xFF
+        )
+    }
+}
+
+/// Snapshot (freeze frame) record of a DTC.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DtcSnapshot {
+    pub dtc: Dtc,
+
+    /// DTCSnapshotRecordNumber
+    pub record_number: u8,
+
+    /// Number of data identifiers in the record
+    pub identifiers: u8,
+
+    /// Data identifiers followed by their values. The length of each value
+    /// depends on the identifier.
+    pub data: Vec<u8>,
+}
+
+/// Reads a 3-byte DTC.
+pub(crate) fn dtc_code(bytes: &[u8]) -> u32 {
+    bytes[..3].iter().fold(0, |n, &b| (n << 8) | b as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status() {
+        for byte in 0..=0xFF_u8 {
+            assert_eq!(u8::from(DtcStatus::from(byte)), byte);
+        }
+        let status = DtcStatus::from(0x2F);
+        assert!(status.test_failed && status.pending && status.confirmed);
+        assert!(status.test_failed_since_last_clear);
+        assert!(!status.warning_indicator_requested);
+    }
+
+    #[test]
+    fn display() {
+        let dtc = Dtc::parse(&[0x03, 0x01, 0x00, 0x08]);
+        assert_eq!(dtc.to_string(), "P0301-00");
+        assert!(dtc.status.confirmed);
+        let dtc = Dtc::parse(&[0xC1, 0x00, 0x87, 0x00]);
+        assert_eq!(dtc.to_string(), "U0100-87");
+    }
+}
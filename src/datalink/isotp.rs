@@ -1,15 +1,11 @@
 use std::cmp;
+use std::collections::BTreeMap;
 use std::convert::TryFrom;
-use std::default::Default;
 use std::io;
-use std::rc::Rc;
 use std::result::Result;
 use std::thread;
-use std::time;
 use std::time::{Duration, Instant};
 
-#[cfg(feature = "socketcan-datalink")]
-use socketcan::CANError;
 use thiserror::Error;
 
 use crate::datalink::can::{Can, Message};
@@ -35,27 +31,178 @@ pub enum IsotpError {
 
     #[error("invalid consecutive frame index")]
     InvalidIndex,
+
+    /// Occurs when a frame is shorter than its protocol control information
+    /// requires, or announces an invalid packet length.
+    #[error("invalid frame length")]
+    InvalidLength,
+
+    /// Occurs when the receiver reports that it cannot store the packet.
+    #[error("receiver buffer overflow")]
+    Overflow,
+
+    /// Occurs when the receiver sends more Wait flow control frames in a row
+    /// than allowed by N_WFTmax.
+    #[error("too many wait flow control frames")]
+    WaitLimitExceeded,
+
+    /// Occurs when an extended or mixed addressing frame carries another
+    /// address than expected.
+    #[error("frame is addressed to another node")]
+    AddressMismatch,
+
+    #[error("packet of {length} bytes is longer than the maximum of {max} bytes")]
+    TooLong { length: usize, max: usize },
+
+    /// Occurs when sending to the functional address of a stack without one.
+    #[error("functional addressing is not supported")]
+    Unsupported,
 }
 
-#[derive(Debug, Copy, Clone)]
+/// Largest packet whose length fits the 12-bit First Frame length. Longer
+/// packets use the 32-bit escape sequence of ISO 15765-2:2016, which older
+/// receivers do not support.
+pub const MAX_SHORT_PACKET_SIZE: usize = 4095;
+
+/// How the target of a frame is addressed.
+///
+/// With extended and mixed addressing the first byte of each CAN message
+/// carries an address, leaving one byte less for the frame.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum AddressingMode {
+    /// The CAN id alone identifies the target.
+    #[default]
+    Normal,
+    /// The first byte is the target address (N_TA). Frames are sent with
+    /// `target` and only frames addressed to `source` are received.
+    Extended { target: u8, source: u8 },
+    /// The first byte is the address extension (N_AE), used in both directions.
+    Mixed { address_extension: u8 },
+}
+
+impl AddressingMode {
+    /// Returns the number of address bytes preceding the PCI.
+    fn address_bytes(self) -> usize {
+        match self {
+            AddressingMode::Normal => 0,
+            _ => 1,
+        }
+    }
+
+    /// Returns the address byte of sent frames.
+    fn send_address(self) -> Option<u8> {
+        match self {
+            AddressingMode::Normal => None,
+            AddressingMode::Extended { target, .. } => Some(target),
+            AddressingMode::Mixed { address_extension } => Some(address_extension),
+        }
+    }
+
+    /// Returns the address byte of received frames.
+    fn receive_address(self) -> Option<u8> {
+        match self {
+            AddressingMode::Normal => None,
+            AddressingMode::Extended { source, .. } => Some(source),
+            AddressingMode::Mixed { address_extension } => Some(address_extension),
+        }
+    }
+
+    /// Returns the largest packet sent in a single frame.
+    pub fn single_capacity(self) -> usize {
+        7 - self.address_bytes()
+    }
+
+    /// Returns the number of data bytes in the first frame of a packet of
+    /// `size` bytes.
+    pub fn first_capacity(self, size: u32) -> usize {
+        if size as usize > MAX_SHORT_PACKET_SIZE {
+            2 - self.address_bytes()
+        } else {
+            6 - self.address_bytes()
+        }
+    }
+
+    /// Returns the number of data bytes in each consecutive frame.
+    pub fn consecutive_capacity(self) -> usize {
+        7 - self.address_bytes()
+    }
+}
+
+/// Default N_Bs timeout: the time to wait for a flow control frame
+pub const DEFAULT_FLOW_CONTROL_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// Default N_Cr timeout: the time to wait for each consecutive frame
+pub const DEFAULT_CONSECUTIVE_FRAME_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// Default N_WFTmax: the number of Wait flow control frames accepted in a row
+pub const DEFAULT_MAX_WAIT_FRAMES: u32 = 10;
+
+/// Functional (broadcast) request id for 11-bit OBD/UDS diagnostics
+pub const OBD_FUNCTIONAL_ID: u32 = 0x7DF;
+
+/// Functional (broadcast) request id for 29-bit OBD/UDS diagnostics
+pub const OBD_FUNCTIONAL_ID_EXTENDED: u32 = 0x18DB_33F1;
+
+/// Returns the physical request id of the ECU that responds with `response_id`,
+/// or `None` if the id is not a standard diagnostic response id. 11-bit
+/// responses 0x7E8-0x7EF map to 0x7E0-0x7E7, and 29-bit normal fixed
+/// responses 0x18DATTSS map to 0x18DASSTT.
+pub fn physical_request_id(response_id: u32) -> Option<u32> {
+    match response_id {
+        0x7E8..=0x7EF => Some(response_id - 8),
+        id if id & 0x1FFF_0000 == 0x18DA_0000 => {
+            Some(0x18DA_0000 | ((id & 0xFF) << 8) | ((id >> 8) & 0xFF))
+        }
+        _ => None,
+    }
+}
+
+/// Flow status of a flow control frame
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum FCFlag {
+    /// Continue to send (CTS)
     Continue = 0,
+    /// Wait for the next flow control frame
     Wait = 1,
+    /// The receiver cannot store a packet of the announced size
     Overflow = 2,
 }
 
-#[derive(Debug)]
+/// ISO 15765-2 frame carried in a single classical CAN message.
+///
+/// Frames are decoded from CAN messages with [`Frame::try_from`] and encoded
+/// with [`Frame::as_can_message`], or with [`Frame::decode`] and
+/// [`Frame::encode`] for other addressing modes. Encoded messages are padded
+/// to 8 bytes.
+///
+/// # Example
+/// ```
+/// use std::convert::TryFrom;
+/// use overboost::datalink::isotp::{AddressingMode, Frame};
+///
+/// let frame = Frame::single(&[0x3E, 0x00], AddressingMode::Normal).unwrap();
+/// let msg = frame.as_can_message(0x7E0).unwrap();
+/// assert_eq!(&msg.data[..3], &[0x02, 0x3E, 0x00]);
+/// assert_eq!(Frame::try_from(msg).unwrap().data(), &[0x3E, 0x00]);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Frame {
-    Single {
-        length: u8,
-        data: [u8; 7],
-    },
+    /// Complete packet of 1 to 7 bytes
+    Single { length: u8, data: [u8; 7] },
+    /// First `length` bytes of a packet of `size` bytes that does not fit a
+    /// single frame. Packets longer than [`MAX_SHORT_PACKET_SIZE`] use a 32-bit
+    /// length and carry fewer bytes. Only the first `length` bytes of `data`
+    /// are used.
     First {
-        size: u16,
+        size: u32,
+        length: u8,
         data: [u8; 6],
     },
+    /// Next `length` bytes of a packet. Only the bytes up to the packet size
+    /// are used.
     Consecutive {
         index: u8,
+        length: u8,
         data: [u8; 7],
     },
     Flow {
@@ -66,55 +213,102 @@ pub enum Frame {
 }
 
 impl Frame {
-    /// Creates a consecutive frame. `data` must be less than 8 bytes long.
-    fn consecutive(data: &[u8], index: u8) -> Frame {
-        assert!(data.len() <= 7);
+    /// Creates a consecutive frame. `data` must be 1 to
+    /// [`AddressingMode::consecutive_capacity`] bytes long. Only the lower 4
+    /// bits of `index` are used.
+    pub fn consecutive(data: &[u8], index: u8, mode: AddressingMode) -> Result<Frame, IsotpError> {
+        check_length(data, mode.consecutive_capacity())?;
         let mut frame_data = [0_u8; 7];
-        frame_data[..data.len()].copy_from_slice(&data);
-        Frame::Consecutive {
-            index,
+        frame_data[..data.len()].copy_from_slice(data);
+        Ok(Frame::Consecutive {
+            index: index & 0x0F,
+            length: data.len() as u8,
             data: frame_data,
-        }
+        })
     }
 
-    /// Creates a first frame. `data` must be less than 7 bytes long.
-    fn first(data: &[u8], size: u16) -> Frame {
-        assert!(data.len() <= 6);
+    /// Creates a first frame of a packet of `size` bytes. The packet must not
+    /// fit a single frame, and `data` must be exactly
+    /// [`AddressingMode::first_capacity`] bytes long, as the receiver takes
+    /// the whole frame as data.
+    pub fn first(data: &[u8], size: u32, mode: AddressingMode) -> Result<Frame, IsotpError> {
+        let capacity = mode.first_capacity(size);
+        check_length(data, capacity)?;
+        if data.len() < capacity || size as usize <= mode.single_capacity() {
+            return Err(IsotpError::InvalidLength);
+        }
         let mut frame_data = [0_u8; 6];
-        frame_data[..data.len()].copy_from_slice(&data);
-        Frame::First {
+        frame_data[..data.len()].copy_from_slice(data);
+        Ok(Frame::First {
             size,
+            length: data.len() as u8,
             data: frame_data,
-        }
+        })
     }
 
-    /// Creates a single frame. `data` must be less than 8 bytes long.
-    fn single(data: &[u8]) -> Frame {
-        assert!(data.len() <= 7);
+    /// Creates a single frame. `data` must be 1 to
+    /// [`AddressingMode::single_capacity`] bytes long.
+    pub fn single(data: &[u8], mode: AddressingMode) -> Result<Frame, IsotpError> {
+        check_length(data, mode.single_capacity())?;
         let mut frame_data = [0_u8; 7];
         frame_data[..data.len()].copy_from_slice(data);
-        Frame::Single {
+        Ok(Frame::Single {
             length: data.len() as u8,
             data: frame_data,
+        })
+    }
+
+    /// Creates a flow control frame.
+    pub fn flow(flag: FCFlag, block_size: u8, separation_time: Duration) -> Frame {
+        Frame::Flow {
+            flag,
+            block_size,
+            separation_time,
+        }
+    }
+
+    /// Returns the payload bytes carried by the frame. Decoded consecutive
+    /// frames include any padding after the end of the packet.
+    pub fn data(&self) -> &[u8] {
+        match self {
+            Frame::Single { length, data } => &data[..*length as usize],
+            Frame::First { length, data, .. } => &data[..*length as usize],
+            Frame::Consecutive { length, data, .. } => &data[..*length as usize],
+            Frame::Flow { .. } => &[],
         }
     }
 
     /// Encodes ISO-TP [`Frame`] to a CAN Message
-    fn as_can_message(&self, id: u32) -> Message {
+    pub fn as_can_message(&self, id: u32) -> Result<Message, IsotpError> {
+        self.encode(id, AddressingMode::Normal)
+    }
+
+    /// Encodes the frame to a CAN message with addressing mode `mode`.
+    /// Returns `TooLong` if the frame's data does not fit the capacity of the
+    /// addressing mode, and `InvalidLength` if a first frame does not fill it.
+    pub fn encode(&self, id: u32, mode: AddressingMode) -> Result<Message, IsotpError> {
+        self.check_capacity(mode)?;
         let mut message_data = [0_u8; 8];
+        let data = self.data();
         match *self {
-            Frame::Single { length, data } => {
-                message_data[0] = length;
-                message_data[1..8].copy_from_slice(&data);
+            Frame::Single { length, .. } => {
+                message_data[0] = length & 0x0F;
+                message_data[1..1 + data.len()].copy_from_slice(data);
+            }
+            Frame::First { size, .. } if size as usize > MAX_SHORT_PACKET_SIZE => {
+                // Escape sequence: FF_DL of 0 followed by a 32-bit length
+                message_data[0] = 0x10;
+                message_data[2..6].copy_from_slice(&size.to_be_bytes());
+                message_data[6..6 + data.len()].copy_from_slice(data);
             }
-            Frame::First { size, data } => {
-                message_data[0] = (1 << 4) | ((size & 0xF00) >> 16) as u8;
-                message_data[1] = (size & 0xFF) as u8;
-                message_data[2..8].copy_from_slice(&data);
+            Frame::First { size, .. } => {
+                message_data[0] = 0x10 | ((size >> 8) & 0x0F) as u8;
+                message_data[1] = size as u8;
+                message_data[2..2 + data.len()].copy_from_slice(data);
             }
-            Frame::Consecutive { index, data } => {
-                message_data[0] = (2 << 4) | index;
-                message_data[1..8].copy_from_slice(&data);
+            Frame::Consecutive { index, .. } => {
+                message_data[0] = 0x20 | (index & 0x0F);
+                message_data[1..1 + data.len()].copy_from_slice(data);
             }
             Frame::Flow {
                 flag,
@@ -122,60 +316,103 @@ impl Frame {
                 separation_time,
             } => {
                 message_data[0] = 0x30 | (flag as u8);
-                message_data[2] = duration_to_st(separation_time);
                 message_data[1] = block_size;
+                message_data[2] = duration_to_st(separation_time);
             }
         };
-        Message {
+        if let Some(address) = mode.send_address() {
+            message_data.copy_within(..7, 1);
+            message_data[0] = address;
+        }
+        Ok(Message {
             id,
             data: message_data,
             len: 8,
-        }
+        })
     }
-}
 
-impl TryFrom<Message> for Frame {
-    type Error = IsotpError;
+    /// Checks that the data of the frame fits addressing mode `mode`, and that
+    /// first frames fill it.
+    fn check_capacity(&self, mode: AddressingMode) -> Result<(), IsotpError> {
+        let length = self.data().len();
+        let max = match *self {
+            Frame::Single { .. } => mode.single_capacity(),
+            Frame::First { size, .. } => mode.first_capacity(size),
+            Frame::Consecutive { .. } => mode.consecutive_capacity(),
+            Frame::Flow { .. } => 0,
+        };
+        if length > max {
+            return Err(IsotpError::TooLong { length, max });
+        }
+        if matches!(self, Frame::First { .. }) && length < max {
+            return Err(IsotpError::InvalidLength);
+        }
+        Ok(())
+    }
 
-    /// Converts from CAN message. Ignores message length. Returns Err(()) for invalid frames.
-    fn try_from(msg: Message) -> Result<Self, Self::Error> {
-        let code = (msg.data[0] & 0xF0) >> 8;
-        match code {
+    /// Decodes a CAN message sent with addressing mode `mode`. Returns
+    /// `AddressMismatch` if the message carries another address than the one
+    /// received by `mode`.
+    pub fn decode(msg: Message, mode: AddressingMode) -> Result<Frame, IsotpError> {
+        let len = cmp::min(msg.len as usize, 8);
+        let offset = mode.address_bytes();
+        if len <= offset {
+            return Err(IsotpError::InvalidLength);
+        }
+        if let Some(address) = mode.receive_address() {
+            if msg.data[0] != address {
+                return Err(IsotpError::AddressMismatch);
+            }
+        }
+        let pci = msg.data[offset];
+        // Bytes following the PCI
+        let payload = &msg.data[offset + 1..len];
+        match pci >> 4 {
             0 => {
-                // Single frame
+                let length = pci & 0x0F;
+                if length == 0
+                    || length as usize > mode.single_capacity()
+                    || length as usize > payload.len()
+                {
+                    return Err(IsotpError::InvalidLength);
+                }
                 let mut data = [0_u8; 7];
-                data.copy_from_slice(&msg.data[1..8]);
-                let length = msg.data[0] & 0x07;
+                data[..length as usize].copy_from_slice(&payload[..length as usize]);
                 Ok(Frame::Single { length, data })
             }
             1 => {
-                // First
-                let size = ((msg.data[0] as u16 & 0x0F) << 16) | msg.data[1] as u16;
-                let mut data = [0_u8; 6];
-                data.copy_from_slice(&msg.data[2..8]);
-                Ok(Frame::First { size, data })
-            }
-            2 => {
-                // Consecutive
-                let index = msg.data[0] & 0x0F;
-                let mut data = [0_u8; 7];
-                data.copy_from_slice(&msg.data[1..8]);
-                Ok(Frame::Consecutive { index, data })
+                if len < 8 {
+                    return Err(IsotpError::InvalidLength);
+                }
+                let short_size = ((pci as u32 & 0x0F) << 8) | payload[0] as u32;
+                if short_size != 0 {
+                    // Packets that fit a single frame must not be segmented
+                    return Frame::first(&payload[1..], short_size, mode);
+                }
+                // Escape sequence. Short packets must use the 12-bit length.
+                let mut size_bytes = [0_u8; 4];
+                size_bytes.copy_from_slice(&payload[1..5]);
+                let size = u32::from_be_bytes(size_bytes);
+                if size as usize <= MAX_SHORT_PACKET_SIZE {
+                    return Err(IsotpError::InvalidLength);
+                }
+                Frame::first(&payload[5..], size, mode)
             }
+            2 => Frame::consecutive(payload, pci & 0x0F, mode),
             3 => {
-                // Flow
-                let flag = match msg.data[0] & 0x03 {
+                if payload.len() < 2 {
+                    return Err(IsotpError::InvalidLength);
+                }
+                let flag = match pci & 0x0F {
                     0 => FCFlag::Continue,
                     1 => FCFlag::Wait,
                     2 => FCFlag::Overflow,
                     _ => return Err(IsotpError::InvalidFcFlag),
                 };
-                let block_size = msg.data[1];
-                let separation_time = msg.data[2];
                 Ok(Frame::Flow {
                     flag,
-                    block_size,
-                    separation_time: st_to_duration(separation_time),
+                    block_size: payload[0],
+                    separation_time: st_to_duration(payload[1]),
                 })
             }
             _ => Err(IsotpError::InvalidFrameId),
@@ -183,69 +420,177 @@ impl TryFrom<Message> for Frame {
     }
 }
 
+impl TryFrom<Message> for Frame {
+    type Error = IsotpError;
+
+    /// Decodes a CAN message. Returns `InvalidLength` if the message is shorter
+    /// than the frame's protocol control information and data, or if the
+    /// frame announces an invalid packet length.
+    fn try_from(msg: Message) -> Result<Self, Self::Error> {
+        Frame::decode(msg, AddressingMode::Normal)
+    }
+}
+
+impl IsotpError {
+    /// Returns true if the error was caused by a frame not being received in time.
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            IsotpError::TimedOut => true,
+            IsotpError::Io(err) => {
+                err.kind() == io::ErrorKind::TimedOut || err.kind() == io::ErrorKind::WouldBlock
+            }
+            _ => false,
+        }
+    }
+}
+
 pub trait Isotp {
     /// Receives an ISO-TP packet
     fn read_isotp(&self) -> Result<Vec<u8>, IsotpError>;
 
+    /// Receives an ISO-TP packet, waiting at most `timeout` for it to start.
+    ///
+    /// The default implementation ignores `timeout` and calls
+    /// [`Isotp::read_isotp`], so the P2 and P2* timings of UDS requests are
+    /// only enforced as far as the read timeout of the stack allows.
+    /// Implementations should override it to wait for the given time.
+    fn read_isotp_timeout(&self, timeout: Duration) -> Result<Vec<u8>, IsotpError> {
+        let _ = timeout;
+        self.read_isotp()
+    }
+
     /// Sends an ISO-TP packet
     fn write_isotp(&self, data: &[u8]) -> Result<(), IsotpError>;
 
+    /// Sends a single frame packet to the functional (broadcast) address.
+    /// Returns [`IsotpError::Unsupported`] if the stack has no functional address.
+    fn write_isotp_functional(&self, data: &[u8]) -> Result<(), IsotpError> {
+        let _ = data;
+        Err(IsotpError::Unsupported)
+    }
+
     fn request_isotp(&self, request: &[u8]) -> Result<Vec<u8>, IsotpError> {
-        self.write_isotp(&request)?;
+        self.write_isotp(request)?;
         self.read_isotp()
     }
 }
 
-/// Converts separation time to [`Duration`]
-fn st_to_duration(st: u8) -> Duration {
-    if st <= 127 {
-        return Duration::from_millis(st as u64);
+/// Converts a separation time (STmin) byte to [`Duration`]. Reserved values
+/// are treated as the longest separation time, 127 ms.
+pub fn st_to_duration(st: u8) -> Duration {
+    match st {
+        0x00..=0x7F => Duration::from_millis(st as u64),
+        0xF1..=0xF9 => Duration::from_micros((st as u64 - 0xF0) * 100),
+        _ => Duration::from_millis(0x7F),
+    }
+}
+
+/// Converts [`Duration`] to a separation time (STmin) byte. Durations are
+/// rounded up to the next representable separation time, up to 127 ms.
+pub fn duration_to_st(duration: Duration) -> u8 {
+    if duration.as_nanos() == 0 {
+        0
+    } else if duration <= Duration::from_micros(900) {
+        0xF0 + duration.as_nanos().div_ceil(100_000) as u8
+    } else {
+        cmp::min(duration.as_nanos().div_ceil(1_000_000), 0x7F) as u8
+    }
+}
+
+/// Checks that `data` holds 1 to `max` bytes.
+fn check_length(data: &[u8], max: usize) -> Result<(), IsotpError> {
+    if data.len() > max {
+        return Err(IsotpError::TooLong {
+            length: data.len(),
+            max,
+        });
+    }
+    if data.is_empty() {
+        return Err(IsotpError::InvalidLength);
     }
-    Duration::from_micros(st as u64)
+    Ok(())
+}
+
+/// Reassembles a multi-frame packet from its first and consecutive frames.
+struct RecvPacket {
+    buffer: Vec<u8>,
+    size: usize,
+    index: u8,
+    /// Consecutive frames received since the last flow control frame
+    block: u8,
 }
 
-/// Converts [`Duration`] to separation time
-fn duration_to_st(duration: Duration) -> u8 {
-    if duration.subsec_micros() <= 900 && duration.subsec_micros() >= 100 {
-        return (cmp::max(duration.subsec_micros() / 100, 1) + 0xF0) as u8;
+impl RecvPacket {
+    /// Starts a packet of `size` bytes from the data of its first frame.
+    fn new(size: u32, data: &[u8]) -> RecvPacket {
+        RecvPacket {
+            buffer: data.to_vec(),
+            size: size as usize,
+            index: 1,
+            block: 0,
+        }
+    }
+
+    /// Appends the data of a consecutive frame. Returns true when the packet
+    /// is complete.
+    fn push(&mut self, index: u8, data: &[u8]) -> Result<bool, IsotpError> {
+        if index != self.index {
+            return Err(IsotpError::InvalidIndex);
+        }
+        let len = cmp::min(self.size - self.buffer.len(), data.len());
+        self.buffer.extend_from_slice(&data[..len]);
+        self.index = (self.index + 1) & 0x0F;
+        Ok(self.eof())
+    }
+
+    fn eof(&self) -> bool {
+        self.buffer.len() >= self.size
     }
-    duration.subsec_micros() as u8
+}
+
+/// Packet started by a single or first frame.
+enum Reception {
+    /// Single frame packet
+    Complete(Vec<u8>),
+    /// Multi-frame packet waiting for its consecutive frames
+    Pending(RecvPacket),
 }
 
 struct SendPacket<'a> {
     buffer: &'a [u8],
     index: u8,
+    mode: AddressingMode,
 }
 
 /// Used for sending mutli-frame packets.
 /// It is NOT used for single-frame packets.
 impl<'a> SendPacket<'a> {
-    fn new(buffer: &[u8]) -> SendPacket {
-        assert!(buffer.len() <= 4095);
-        SendPacket { buffer, index: 0 }
-    }
-
-    fn first_frame(&mut self) -> Frame {
-        let len = cmp::min(self.buffer.len(), 6);
-        let mut data = [0_u8; 6];
-        data.copy_from_slice(&self.buffer[..len]);
-        let frame = Frame::First {
-            size: self.buffer.len() as u16,
-            data,
-        };
+    fn new(buffer: &[u8], mode: AddressingMode) -> SendPacket<'_> {
+        SendPacket {
+            buffer,
+            index: 0,
+            mode,
+        }
+    }
+
+    /// Returns `TooLong` if the packet length does not fit in 32 bits.
+    fn first_frame(&mut self) -> Result<Frame, IsotpError> {
+        let size = u32::try_from(self.buffer.len()).map_err(|_| IsotpError::TooLong {
+            length: self.buffer.len(),
+            max: u32::MAX as usize,
+        })?;
+        let len = self.mode.first_capacity(size);
+        let frame = Frame::first(&self.buffer[..len], size, self.mode)?;
         self.buffer = &self.buffer[len..];
         self.index = 1;
-        frame
+        Ok(frame)
     }
 
     fn next_consec_frame(&mut self) -> Frame {
-        let len = cmp::min(self.buffer.len(), 7);
-        let frame = Frame::consecutive(&self.buffer[..len], self.index);
+        let len = cmp::min(self.buffer.len(), self.mode.consecutive_capacity());
+        let frame = Frame::consecutive(&self.buffer[..len], self.index, self.mode).unwrap();
         self.buffer = &self.buffer[len..];
-        self.index += 1;
-        if self.index == 16 {
-            self.index = 0;
-        }
+        self.index = (self.index + 1) & 0x0F;
         frame
     }
 
@@ -260,6 +605,24 @@ pub struct IsotpCan<C: Can> {
     pub source_id: u32,
     pub dest_id: u32,
     pub timeout: Duration,
+    /// Id of functional (broadcast) requests, e.g. [`OBD_FUNCTIONAL_ID`]
+    pub functional_id: Option<u32>,
+    /// N_Bs: time to wait for each flow control frame when sending
+    pub flow_control_timeout: Duration,
+    /// N_WFTmax: Wait flow control frames accepted in a row before aborting
+    pub max_wait_frames: u32,
+    /// Addressing mode of sent and received frames
+    pub addressing: AddressingMode,
+    /// Longest packet sent or accepted. Defaults to [`MAX_SHORT_PACKET_SIZE`];
+    /// raise it for peers that support 32-bit packet lengths.
+    pub max_packet_size: usize,
+    /// Block size sent in flow control frames when receiving. 0 lets the
+    /// sender send all consecutive frames without further flow control.
+    pub block_size: u8,
+    /// Minimum separation time between consecutive frames requested when receiving
+    pub separation_time: Duration,
+    /// N_Cr: time to wait for each consecutive frame when receiving
+    pub consecutive_frame_timeout: Duration,
 }
 
 impl<C: Can> IsotpCan<C> {
@@ -269,96 +632,278 @@ impl<C: Can> IsotpCan<C> {
             source_id,
             dest_id,
             timeout,
+            functional_id: None,
+            addressing: AddressingMode::Normal,
+            flow_control_timeout: DEFAULT_FLOW_CONTROL_TIMEOUT,
+            max_wait_frames: DEFAULT_MAX_WAIT_FRAMES,
+            max_packet_size: MAX_SHORT_PACKET_SIZE,
+            block_size: 0,
+            separation_time: Duration::from_millis(0),
+            consecutive_frame_timeout: DEFAULT_CONSECUTIVE_FRAME_TIMEOUT,
         }
     }
 
-    fn send_frame(&self, frame: &Frame) -> Result<(), IsotpError> {
-        self.can.send_msg(&frame.as_can_message(self.source_id))?;
-        Ok(())
+    /// Sets the id used for functional (broadcast) requests.
+    pub fn with_functional_id(mut self, functional_id: u32) -> IsotpCan<C> {
+        self.functional_id = Some(functional_id);
+        self
+    }
+
+    /// Sets the addressing mode of sent and received frames.
+    pub fn with_addressing(mut self, addressing: AddressingMode) -> IsotpCan<C> {
+        self.addressing = addressing;
+        self
     }
 
-    fn recv_frame(&self) -> Result<Frame, IsotpError> {
+    /// Sends a single frame functional request and collects the responses of
+    /// all ECUs received within `window`, keyed by responder id. Only standard
+    /// diagnostic response ids (see [`physical_request_id`]) are collected.
+    /// Multi-frame responses that are not complete when the window ends are
+    /// dropped. If an ECU responds more than once, its last response is kept.
+    pub fn request_isotp_functional(
+        &self,
+        request: &[u8],
+        window: Duration,
+    ) -> Result<BTreeMap<u32, Vec<u8>>, IsotpError> {
+        self.request_isotp_functional_from(request, window, physical_request_id)
+    }
+
+    /// Like [`request_isotp_functional`](Self::request_isotp_functional), but
+    /// `request_id` selects the responders: it maps the id of a response to
+    /// the id its flow control frames are sent on, or returns `None` to ignore
+    /// the response.
+    ///
+    /// Multi-frame responses are received like [`Isotp::read_isotp`], using
+    /// the block size, separation time, N_Cr and maximum packet size of the
+    /// stack. A response that fails, e.g. because it is too long, is dropped
+    /// without affecting the other ECUs.
+    pub fn request_isotp_functional_from<F>(
+        &self,
+        request: &[u8],
+        window: Duration,
+        request_id: F,
+    ) -> Result<BTreeMap<u32, Vec<u8>>, IsotpError>
+        where
+            F: Fn(u32) -> Option<u32>,
+    {
+        self.write_isotp_functional(request)?;
+
+        let mut responses = BTreeMap::new();
+        // Incomplete packets and when their last frame was received
+        let mut pending: BTreeMap<u32, (RecvPacket, Instant)> = BTreeMap::new();
         let start_time = Instant::now();
         loop {
-            let msg = self.can.read(self.timeout)?;
-            if msg.id == self.dest_id {
-                return Ok(Frame::try_from(msg)?);
+            let elapsed = start_time.elapsed();
+            if elapsed >= window {
+                break;
             }
-            if start_time.elapsed() >= self.timeout {
-                return Err(IsotpError::TimedOut);
+            let msg = match self.can.read(window - elapsed) {
+                Ok(msg) => msg,
+                Err(err) => {
+                    let err = IsotpError::from(err);
+                    if err.is_timeout() {
+                        break;
+                    }
+                    return Err(err);
+                }
+            };
+            let flow_id = match request_id(msg.id) {
+                Some(id) => id,
+                None => continue,
+            };
+            let responder = msg.id;
+            let frame = match Frame::decode(msg, self.addressing) {
+                Ok(frame) => frame,
+                Err(_) => continue,
+            };
+            let received = match frame {
+                Frame::Consecutive { .. } => match pending.remove(&responder) {
+                    Some((mut packet, last)) if last.elapsed() < self.consecutive_frame_timeout => {
+                        self.recv_consecutive(&mut packet, frame, flow_id)
+                            .map(|complete| {
+                                if complete {
+                                    Reception::Complete(packet.buffer)
+                                } else {
+                                    Reception::Pending(packet)
+                                }
+                            })
+                    }
+                    // No packet in progress, or N_Cr expired
+                    _ => continue,
+                },
+                _ => {
+                    pending.remove(&responder);
+                    self.recv_start(frame, flow_id)
+                }
+            };
+            match received {
+                Ok(Reception::Complete(data)) => {
+                    responses.insert(responder, data);
+                }
+                Ok(Reception::Pending(packet)) => {
+                    pending.insert(responder, (packet, Instant::now()));
+                }
+                Err(IsotpError::Io(err)) => return Err(err.into()),
+                // A broken response does not affect the other ECUs
+                Err(_) => {}
             }
         }
-        Err(IsotpError::TimedOut)
+        Ok(responses)
     }
 
-    /// Returns (flag, block_size, separation_time)
-    fn recv_flow_control_frame(&self) -> Result<(FCFlag, u8, Duration), IsotpError> {
-        let frame = self.recv_frame()?;
+    /// Starts receiving a packet from its single or first frame. A first
+    /// frame is answered on `flow_id` with a Continue flow control frame, or
+    /// with Overflow if the packet is longer than `max_packet_size`.
+    fn recv_start(&self, frame: Frame, flow_id: u32) -> Result<Reception, IsotpError> {
         match frame {
-            Frame::Flow {
-                flag,
-                block_size,
-                separation_time,
-            } => Ok((flag, block_size, separation_time)),
+            Frame::Single { .. } => Ok(Reception::Complete(frame.data().to_vec())),
+            Frame::First { size, .. } if size as usize > self.max_packet_size => {
+                let overflow = Frame::flow(FCFlag::Overflow, 0, Duration::from_millis(0));
+                self.send_frame_to(&overflow, flow_id)?;
+                Err(IsotpError::TooLong {
+                    length: size as usize,
+                    max: self.max_packet_size,
+                })
+            }
+            Frame::First { size, .. } => {
+                let flow = Frame::flow(FCFlag::Continue, self.block_size, self.separation_time);
+                self.send_frame_to(&flow, flow_id)?;
+                Ok(Reception::Pending(RecvPacket::new(size, frame.data())))
+            }
             _ => Err(IsotpError::UnexpectedFrame),
         }
     }
+
+    /// Appends a consecutive frame to `packet`, allowing the next block with
+    /// a flow control frame on `flow_id` after every `block_size` frames.
+    /// Returns true when the packet is complete.
+    fn recv_consecutive(
+        &self,
+        packet: &mut RecvPacket,
+        frame: Frame,
+        flow_id: u32,
+    ) -> Result<bool, IsotpError> {
+        let complete = match frame {
+            Frame::Consecutive { index, .. } => packet.push(index, frame.data())?,
+            _ => return Err(IsotpError::UnexpectedFrame),
+        };
+        if self.block_size > 0 && !complete {
+            packet.block += 1;
+            if packet.block == self.block_size {
+                let flow = Frame::flow(FCFlag::Continue, self.block_size, self.separation_time);
+                self.send_frame_to(&flow, flow_id)?;
+                packet.block = 0;
+            }
+        }
+        Ok(complete)
+    }
+
+    fn send_frame(&self, frame: &Frame) -> Result<(), IsotpError> {
+        self.send_frame_to(frame, self.source_id)
+    }
+
+    fn send_frame_to(&self, frame: &Frame, id: u32) -> Result<(), IsotpError> {
+        self.can.send_msg(&frame.encode(id, self.addressing)?)?;
+        Ok(())
+    }
+
+    /// Receives the next frame from `dest_id`, waiting at most `timeout`.
+    fn recv_frame_timeout(&self, timeout: Duration) -> Result<Frame, IsotpError> {
+        let start_time = Instant::now();
+        loop {
+            let elapsed = start_time.elapsed();
+            if elapsed >= timeout {
+                return Err(IsotpError::TimedOut);
+            }
+            let msg = self.can.read(timeout - elapsed)?;
+            if msg.id == self.dest_id {
+                match Frame::decode(msg, self.addressing) {
+                    // Frame for another node sharing the id
+                    Err(IsotpError::AddressMismatch) => {}
+                    res => return res,
+                }
+            }
+        }
+    }
+
+    /// Waits for a Continue flow control frame and returns (block_size,
+    /// separation_time). Each flow control frame must arrive within N_Bs, and
+    /// at most N_WFTmax Wait frames are accepted.
+    fn recv_flow_control_frame(&self) -> Result<(u8, Duration), IsotpError> {
+        let mut waits = 0;
+        loop {
+            match self.recv_frame_timeout(self.flow_control_timeout)? {
+                Frame::Flow {
+                    flag: FCFlag::Continue,
+                    block_size,
+                    separation_time,
+                } => return Ok((block_size, separation_time)),
+                Frame::Flow {
+                    flag: FCFlag::Wait, ..
+                } => {
+                    waits += 1;
+                    if waits > self.max_wait_frames {
+                        return Err(IsotpError::WaitLimitExceeded);
+                    }
+                }
+                Frame::Flow {
+                    flag: FCFlag::Overflow,
+                    ..
+                } => return Err(IsotpError::Overflow),
+                _ => return Err(IsotpError::UnexpectedFrame),
+            }
+        }
+    }
 }
 
 impl<C: Can> Isotp for IsotpCan<C> {
     fn read_isotp(&self) -> Result<Vec<u8>, IsotpError> {
-        // Receive first or single frame
-        let frame = self.recv_frame()?;
-        match frame {
-            Frame::Single { length, data } => Ok(data[..cmp::min(length as usize, 7)].to_vec()),
-            Frame::First { size, data } => {
-                let mut buffer = data[..cmp::min(size as usize, 6)].to_vec();
-                let mut remaining = size as usize - buffer.len();
-                // Send the flow control frame
-                self.send_frame(&Frame::Flow {
-                    flag: FCFlag::Continue,
-                    block_size: 0,
-                    separation_time: st_to_duration(0),
-                })?;
-
-                // Wait for all consecutive packets
-                let mut index = 1;
-                while remaining > 0 {
-                    let (msg_index, data) = match self.recv_frame()? {
-                        Frame::Consecutive { index, data } => (index, data),
-                        _ => return Err(IsotpError::UnexpectedFrame),
-                    };
-                    if msg_index != index {
-                        // Invalid index
-                        return Err(IsotpError::InvalidIndex);
-                    }
+        self.read_isotp_timeout(self.timeout)
+    }
 
-                    let len = cmp::min(remaining, 7);
-                    buffer.extend_from_slice(&data);
-                    remaining -= len;
+    /// Waits at most `timeout` for the single or first frame. Each consecutive
+    /// frame must arrive within N_Cr.
+    fn read_isotp_timeout(&self, timeout: Duration) -> Result<Vec<u8>, IsotpError> {
+        // Receive first or single frame
+        let frame = self.recv_frame_timeout(timeout)?;
+        let mut packet = match self.recv_start(frame, self.source_id)? {
+            Reception::Complete(data) => return Ok(data),
+            Reception::Pending(packet) => packet,
+        };
 
-                    index += 1;
-                    if index == 16 {
-                        index = 0;
-                    }
-                }
-                Ok(buffer)
+        // Wait for all consecutive frames
+        loop {
+            let frame = self.recv_frame_timeout(self.consecutive_frame_timeout)?;
+            if self.recv_consecutive(&mut packet, frame, self.source_id)? {
+                return Ok(packet.buffer);
             }
-            _ => Err(IsotpError::UnexpectedFrame),
         }
     }
 
+    fn write_isotp_functional(&self, data: &[u8]) -> Result<(), IsotpError> {
+        let functional_id = self.functional_id.ok_or(IsotpError::Unsupported)?;
+        self.can.send_msg(
+            &Frame::single(data, self.addressing)?.encode(functional_id, self.addressing)?,
+        )?;
+        Ok(())
+    }
+
     fn write_isotp(&self, data: &[u8]) -> Result<(), IsotpError> {
-        if data.len() <= 7 {
+        if data.len() <= self.addressing.single_capacity() {
             // Send a single frame
-            self.send_frame(&Frame::single(data))?;
+            self.send_frame(&Frame::single(data, self.addressing)?)?;
+        } else if data.len() > self.max_packet_size {
+            return Err(IsotpError::TooLong {
+                length: data.len(),
+                max: self.max_packet_size,
+            });
         } else {
-            let mut packet = SendPacket::new(&data);
+            let mut packet = SendPacket::new(data, self.addressing);
             // Send a first frame
-            self.send_frame(&packet.first_frame())?;
+            self.send_frame(&packet.first_frame()?)?;
             // Get flow control and send consecutive frames
 
-            let (mut flag, mut block_size, mut separation_time) = self.recv_flow_control_frame()?;
+            let (mut block_size, mut separation_time) = self.recv_flow_control_frame()?;
             while !packet.eof() {
                 // Loop until the buffer is empty
                 if separation_time != Duration::new(0, 0) {
@@ -371,9 +916,7 @@ impl<C: Can> Isotp for IsotpCan<C> {
                     block_size -= 1;
                     if block_size == 0 {
                         // Get the next flow control packet
-                        let (f_flag, f_block_size, f_separation_time) =
-                            self.recv_flow_control_frame()?;
-                        flag = f_flag;
+                        let (f_block_size, f_separation_time) = self.recv_flow_control_frame()?;
                         block_size = f_block_size;
                         separation_time = f_separation_time;
                     }
@@ -386,13 +929,756 @@ impl<C: Can> Isotp for IsotpCan<C> {
 
 #[cfg(test)]
 mod tests {
-    #[cfg(unix)]
+    #[cfg(feature = "socketcan-datalink")]
     use socketcan::CANSocket;
 
+    use proptest::prelude::*;
+
+    use crate::datalink::can::{VirtualBus, VirtualCan};
+
+    use super::*;
+
+    /// Addressing modes used by the property tests. Extended addressing
+    /// sends and receives the same address so frames can be decoded.
+    const MODES: [AddressingMode; 3] = [
+        AddressingMode::Normal,
+        AddressingMode::Extended {
+            target: 0x12,
+            source: 0x12,
+        },
+        AddressingMode::Mixed {
+            address_extension: 0x55,
+        },
+    ];
+
+    /// Splits `data` into the frames sent by [`IsotpCan::write_isotp`].
+    fn segment(data: &[u8], mode: AddressingMode) -> Vec<Frame> {
+        if data.len() <= mode.single_capacity() {
+            return vec![Frame::single(data, mode).unwrap()];
+        }
+        let mut packet = SendPacket::new(data, mode);
+        let mut frames = vec![packet.first_frame().unwrap()];
+        while !packet.eof() {
+            frames.push(packet.next_consec_frame());
+        }
+        frames
+    }
+
+    /// Reassembles a packet from encoded frames.
+    fn reassemble(messages: &[Message], mode: AddressingMode) -> Vec<u8> {
+        let mut frames = messages
+            .iter()
+            .map(|msg| Frame::decode(msg.clone(), mode).unwrap());
+        let first = frames.next().unwrap();
+        match first {
+            Frame::Single { .. } => first.data().to_vec(),
+            Frame::First { size, .. } => {
+                let mut packet = RecvPacket::new(size, first.data());
+                for frame in frames {
+                    match frame {
+                        Frame::Consecutive { index, .. } => {
+                            packet.push(index, frame.data()).unwrap();
+                        }
+                        frame => panic!("unexpected frame {:?}", frame),
+                    }
+                }
+                assert!(packet.eof());
+                packet.buffer
+            }
+            frame => panic!("unexpected frame {:?}", frame),
+        }
+    }
+
+    /// Asserts that `frame` survives encoding and decoding.
+    fn assert_round_trip(frame: Frame) {
+        let msg = frame.as_can_message(0x7E0).unwrap();
+        assert_eq!(msg.len, 8);
+        assert_eq!(Frame::try_from(msg).unwrap(), frame);
+    }
+
+    proptest! {
+        #[test]
+        fn segment_round_trip(
+            data in prop::collection::vec(any::<u8>(), 1..=4095),
+            mode in prop::sample::select(MODES.to_vec()),
+        ) {
+            let messages: Vec<Message> = segment(&data, mode)
+                .iter()
+                .map(|frame| frame.encode(0x7E0, mode).unwrap())
+                .collect();
+            let (first, consecutive) = (mode.first_capacity(0), mode.consecutive_capacity());
+            let frames = if data.len() <= mode.single_capacity() {
+                1
+            } else {
+                1 + (data.len() - first).div_ceil(consecutive)
+            };
+            prop_assert_eq!(messages.len(), frames);
+            prop_assert_eq!(reassemble(&messages, mode), data);
+        }
+
+        #[test]
+        fn decode_encode(
+            data in any::<[u8; 8]>(),
+            len in 0_u8..=8,
+            mode in prop::sample::select(MODES.to_vec()),
+        ) {
+            let msg = Message { id: 0x7E8, data, len };
+            if let Ok(frame) = Frame::decode(msg.clone(), mode) {
+                let encoded = frame.encode(0x7E8, mode).unwrap();
+                // Padding becomes part of decoded consecutive frames, after
+                // which encoding is stable
+                let decoded = Frame::decode(encoded.clone(), mode).unwrap();
+                prop_assert!(decoded.data().starts_with(frame.data()));
+                prop_assert_eq!(decoded.encode(0x7E8, mode).unwrap().data, encoded.data);
+                // The address and protocol control information are preserved
+                let pci = mode.address_bytes();
+                prop_assert_eq!(&encoded.data[..=pci], &msg.data[..=pci]);
+            }
+        }
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        let data: Vec<u8> = (1..=7).collect();
+        for len in 1..=7 {
+            let frame = Frame::single(&data[..len], AddressingMode::Normal).unwrap();
+            assert_eq!(frame.data(), &data[..len]);
+            assert_round_trip(frame);
+        }
+        for size in 8..=4095 {
+            let frame = Frame::first(&data[..6], size, AddressingMode::Normal).unwrap();
+            let msg = frame.as_can_message(0x7E0).unwrap();
+            assert_eq!(
+                ((msg.data[0] as u32 & 0x0F) << 8) | msg.data[1] as u32,
+                size
+            );
+            assert_round_trip(frame);
+        }
+        for &size in &[4096, 0xFFFF, 0x10000, u32::MAX] {
+            let frame = Frame::first(&data[..2], size, AddressingMode::Normal).unwrap();
+            assert_eq!(frame.data(), &data[..2]);
+            let msg = frame.as_can_message(0x7E0).unwrap();
+            assert_eq!(&msg.data[..2], &[0x10, 0x00]);
+            assert_eq!(&msg.data[2..6], &size.to_be_bytes());
+            assert_round_trip(frame);
+        }
+        for index in 0..=15 {
+            let frame = Frame::consecutive(&data, index, AddressingMode::Normal).unwrap();
+            assert_round_trip(frame);
+        }
+        // Short consecutive frames are padded when encoded
+        let frame = Frame::consecutive(&data[..2], 1, AddressingMode::Normal).unwrap();
+        assert_eq!(frame.data(), &data[..2]);
+        assert_eq!(
+            frame.as_can_message(0x7E0).unwrap().data,
+            [0x21, 1, 2, 0, 0, 0, 0, 0]
+        );
+        for &flag in &[FCFlag::Continue, FCFlag::Wait, FCFlag::Overflow] {
+            for block_size in 0..=0xFF {
+                for st in (0x00..=0x7F).chain(0xF1..=0xF9) {
+                    let frame = Frame::flow(flag, block_size, st_to_duration(st));
+                    assert_eq!(
+                        frame.as_can_message(0x7E0).unwrap().data[..3],
+                        [0x30 | flag as u8, block_size, st]
+                    );
+                    assert_round_trip(frame);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn addressing_modes() {
+        let extended = AddressingMode::Extended {
+            target: 0x12,
+            source: 0xF1,
+        };
+        let msg = Frame::single(&[0x3E, 0x00], extended)
+            .unwrap()
+            .encode(0x6F1, extended)
+            .unwrap();
+        assert_eq!(&msg.data[..4], &[0x12, 0x02, 0x3E, 0x00]);
+        // The ECU's frames are addressed to the tester
+        assert!(matches!(
+            Frame::decode(msg.clone(), extended),
+            Err(IsotpError::AddressMismatch)
+        ));
+        let ecu = AddressingMode::Extended {
+            target: 0xF1,
+            source: 0x12,
+        };
+        assert_eq!(Frame::decode(msg, ecu).unwrap().data(), &[0x3E, 0x00]);
+
+        let mixed = AddressingMode::Mixed {
+            address_extension: 0x55,
+        };
+        let data: Vec<u8> = (1..=7).collect();
+        let mut packet = SendPacket::new(&data, mixed);
+        let first = packet.first_frame().unwrap().encode(0x7E0, mixed).unwrap();
+        assert_eq!(first.data, [0x55, 0x10, 0x07, 1, 2, 3, 4, 5]);
+        let consecutive = packet.next_consec_frame().encode(0x7E0, mixed).unwrap();
+        assert_eq!(&consecutive.data[..4], &[0x55, 0x21, 6, 7]);
+        assert!(packet.eof());
+        let flow = Frame::flow(FCFlag::Continue, 8, Duration::from_millis(20));
+        assert_eq!(
+            &flow.encode(0x7E8, mixed).unwrap().data[..4],
+            &[0x55, 0x30, 0x08, 0x14]
+        );
+
+        // Frames are built for an addressing mode
+        assert!(matches!(
+            Frame::single(&data, mixed),
+            Err(IsotpError::TooLong { length: 7, max: 6 })
+        ));
+        assert!(matches!(
+            Frame::first(&data[..6], 8, mixed),
+            Err(IsotpError::TooLong { length: 6, max: 5 })
+        ));
+        assert!(matches!(
+            Frame::first(&data[..4], 8, mixed),
+            Err(IsotpError::InvalidLength)
+        ));
+        assert!(matches!(
+            Frame::consecutive(&data, 1, extended),
+            Err(IsotpError::TooLong { length: 7, max: 6 })
+        ));
+        // and may not fit another one
+        let normal = AddressingMode::Normal;
+        assert!(matches!(
+            Frame::single(&data, normal).unwrap().encode(0x7E0, mixed),
+            Err(IsotpError::TooLong { length: 7, max: 6 })
+        ));
+        assert!(matches!(
+            Frame::first(&data[..6], 8, normal)
+                .unwrap()
+                .encode(0x7E0, mixed),
+            Err(IsotpError::TooLong { length: 6, max: 5 })
+        ));
+        assert!(matches!(
+            Frame::first(&data[..5], 8, mixed)
+                .unwrap()
+                .encode(0x7E0, normal),
+            Err(IsotpError::InvalidLength)
+        ));
+
+        // 7 bytes do not fit a single frame, and 4096 byte packets only carry
+        // one byte in the first frame
+        let msg = Message {
+            id: 0x7E8,
+            data: [0x55, 0x07, 1, 2, 3, 4, 5, 6],
+            len: 8,
+        };
+        assert!(matches!(
+            Frame::decode(msg, mixed),
+            Err(IsotpError::InvalidLength)
+        ));
+        assert_eq!(mixed.first_capacity(4096), 1);
+        let msg = Message {
+            id: 0x7E8,
+            data: [0x55, 0x10, 0x00, 0x00, 0x00, 0x10, 0x00, 0xAA],
+            len: 8,
+        };
+        let frame = Frame::decode(msg, mixed).unwrap();
+        assert!(matches!(frame, Frame::First { size: 4096, .. }));
+        assert_eq!(frame.data(), &[0xAA]);
+    }
+
+    #[test]
+    fn invalid_frames() {
+        let decode = |data: &[u8]| {
+            let mut msg = Message {
+                id: 0x7E8,
+                len: data.len() as u8,
+                ..Default::default()
+            };
+            msg.data[..data.len()].copy_from_slice(data);
+            Frame::try_from(msg)
+        };
+        // Single frames of 0 bytes, longer than 7 bytes or longer than the message
+        assert!(matches!(decode(&[0x00; 8]), Err(IsotpError::InvalidLength)));
+        assert!(matches!(decode(&[0x08; 8]), Err(IsotpError::InvalidLength)));
+        assert!(matches!(
+            decode(&[0x03, 0x01, 0x02]),
+            Err(IsotpError::InvalidLength)
+        ));
+        // First frames of less than 8 bytes or truncated
+        assert!(matches!(
+            decode(&[0x10, 0x07, 0, 0, 0, 0, 0, 0]),
+            Err(IsotpError::InvalidLength)
+        ));
+        assert!(matches!(
+            decode(&[0x10, 0x08, 0, 0]),
+            Err(IsotpError::InvalidLength)
+        ));
+        // Escaped first frames of packets that fit the 12-bit length
+        assert!(matches!(
+            decode(&[0x10, 0x00, 0x00, 0x00, 0x0F, 0xFF, 0, 0]),
+            Err(IsotpError::InvalidLength)
+        ));
+        assert!(matches!(decode(&[0x20]), Err(IsotpError::InvalidLength)));
+        assert!(matches!(
+            decode(&[0x30, 0x00]),
+            Err(IsotpError::InvalidLength)
+        ));
+        assert!(matches!(decode(&[]), Err(IsotpError::InvalidLength)));
+        // Reserved flow status and frame types
+        assert!(matches!(
+            decode(&[0x33, 0, 0]),
+            Err(IsotpError::InvalidFcFlag)
+        ));
+        assert!(matches!(
+            decode(&[0x40; 8]),
+            Err(IsotpError::InvalidFrameId)
+        ));
+
+        // Short consecutive frames only carry the bytes of the message
+        let frame = decode(&[0x21, 0xAA, 0xBB]).unwrap();
+        assert_eq!(frame.data(), &[0xAA, 0xBB]);
+        // Reserved separation times are treated as 127 ms
+        match decode(&[0x30, 0x00, 0x80]).unwrap() {
+            Frame::Flow {
+                separation_time, ..
+            } => assert_eq!(separation_time, Duration::from_millis(127)),
+            frame => panic!("unexpected frame {:?}", frame),
+        }
+
+        let normal = AddressingMode::Normal;
+        assert!(matches!(
+            Frame::single(&[], normal),
+            Err(IsotpError::InvalidLength)
+        ));
+        assert!(matches!(
+            Frame::single(&[0; 8], normal),
+            Err(IsotpError::TooLong { length: 8, max: 7 })
+        ));
+        assert!(matches!(
+            Frame::first(&[0; 6], 7, normal),
+            Err(IsotpError::InvalidLength)
+        ));
+        assert!(matches!(
+            Frame::first(&[0; 6], 4096, normal),
+            Err(IsotpError::TooLong { length: 6, max: 2 })
+        ));
+        assert!(matches!(
+            Frame::consecutive(&[], 1, normal),
+            Err(IsotpError::InvalidLength)
+        ));
+    }
+
     #[test]
-    #[cfg(unix)]
+    fn separation_time() {
+        for st in (0x00..=0x7F).chain(0xF1..=0xF9) {
+            assert_eq!(duration_to_st(st_to_duration(st)), st);
+        }
+        for st in (0x80..=0xF0).chain(0xFA..=0xFF) {
+            assert_eq!(st_to_duration(st), Duration::from_millis(127));
+        }
+        assert_eq!(st_to_duration(0xF5), Duration::from_micros(500));
+        // Durations are rounded up
+        assert_eq!(duration_to_st(Duration::from_micros(50)), 0xF1);
+        assert_eq!(duration_to_st(Duration::from_micros(150)), 0xF2);
+        assert_eq!(duration_to_st(Duration::from_micros(950)), 1);
+        assert_eq!(duration_to_st(Duration::from_micros(1500)), 2);
+        assert_eq!(duration_to_st(Duration::from_secs(1)), 0x7F);
+    }
+
+    /// Waits for a first frame and answers it with a flow control frame of
+    /// each of `flags`, sleeping `delay` before each.
+    fn answer_first_frame(ecu: &VirtualCan, flags: &[FCFlag], delay: Duration) {
+        let msg = ecu.read(Duration::from_secs(1)).unwrap();
+        assert_eq!(msg.data[0] & 0xF0, 0x10);
+        for &flag in flags {
+            thread::sleep(delay);
+            let frame = Frame::flow(flag, 0, Duration::from_millis(0));
+            ecu.send_msg(&frame.as_can_message(0x7E8).unwrap()).unwrap();
+        }
+    }
+
+    #[test]
+    fn flow_control() {
+        let bus = VirtualBus::new();
+        let mut tester = IsotpCan::new(bus.endpoint(), 0x7E0, 0x7E8, Duration::from_millis(100));
+        tester.flow_control_timeout = Duration::from_millis(100);
+        tester.max_wait_frames = 2;
+        let ecu = bus.endpoint();
+
+        let handle = thread::spawn(move || {
+            // Wait frames within N_Bs and N_WFTmax
+            let flags = [FCFlag::Wait, FCFlag::Wait, FCFlag::Continue];
+            answer_first_frame(&ecu, &flags, Duration::from_millis(20));
+            for _ in 0..2 {
+                let msg = ecu.read(Duration::from_secs(1)).unwrap();
+                assert_eq!(msg.data[0] & 0xF0, 0x20);
+            }
+
+            answer_first_frame(&ecu, &[FCFlag::Wait; 3], Duration::from_millis(0));
+            answer_first_frame(&ecu, &[FCFlag::Overflow], Duration::from_millis(0));
+            // The flow control frame is far too late
+            answer_first_frame(&ecu, &[FCFlag::Continue], Duration::from_millis(400));
+        });
+
+        tester.write_isotp(&[0; 20]).unwrap();
+        assert!(matches!(
+            tester.write_isotp(&[0; 20]),
+            Err(IsotpError::WaitLimitExceeded)
+        ));
+        assert!(matches!(
+            tester.write_isotp(&[0; 20]),
+            Err(IsotpError::Overflow)
+        ));
+        let start = Instant::now();
+        assert!(tester.write_isotp(&[0; 20]).unwrap_err().is_timeout());
+        assert!(start.elapsed() < Duration::from_millis(300));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn receive_parameters() {
+        let bus = VirtualBus::new();
+        let mut tester = IsotpCan::new(bus.endpoint(), 0x7E0, 0x7E8, Duration::from_secs(1));
+        tester.block_size = 2;
+        tester.separation_time = Duration::from_millis(5);
+        tester.consecutive_frame_timeout = Duration::from_millis(50);
+        let ecu = bus.endpoint();
+
+        let handle = thread::spawn(move || {
+            let expect_flow_control = || {
+                let msg = ecu.read(Duration::from_secs(1)).unwrap();
+                assert_eq!((msg.id, &msg.data[..3]), (0x7E0, &[0x30, 0x02, 0x05][..]));
+            };
+            // 30 bytes are sent in 4 consecutive frames, 2 per block
+            let data: Vec<u8> = (0..30).collect();
+            let mut packet = SendPacket::new(&data, AddressingMode::Normal);
+            ecu.send_msg(&packet.first_frame().unwrap().as_can_message(0x7E8).unwrap())
+                .unwrap();
+            for _ in 0..2 {
+                expect_flow_control();
+                for _ in 0..2 {
+                    ecu.send_msg(&packet.next_consec_frame().as_can_message(0x7E8).unwrap())
+                        .unwrap();
+                }
+            }
+            // No flow control after the last block
+            assert!(ecu.read(Duration::from_millis(20)).is_err());
+
+            // Stop sending after the first block
+            let mut packet = SendPacket::new(&data, AddressingMode::Normal);
+            ecu.send_msg(&packet.first_frame().unwrap().as_can_message(0x7E8).unwrap())
+                .unwrap();
+            expect_flow_control();
+            for _ in 0..2 {
+                ecu.send_msg(&packet.next_consec_frame().as_can_message(0x7E8).unwrap())
+                    .unwrap();
+            }
+        });
+
+        assert_eq!(tester.read_isotp().unwrap(), (0..30).collect::<Vec<u8>>());
+        let start = Instant::now();
+        // N_Cr is shorter than the stack's timeout
+        assert!(tester.read_isotp().unwrap_err().is_timeout());
+        assert!(start.elapsed() < Duration::from_millis(500));
+        handle.join().unwrap();
+
+        // Interoperates with the sender
+        let ecu = IsotpCan::new(bus.endpoint(), 0x7E8, 0x7E0, Duration::from_millis(100));
+        let data: Vec<u8> = (0..4095).map(|i| i as u8).collect();
+        let handle = {
+            let data = data.clone();
+            thread::spawn(move || ecu.write_isotp(&data).unwrap())
+        };
+        tester.separation_time = Duration::from_micros(100);
+        tester.block_size = 16;
+        assert_eq!(tester.read_isotp().unwrap(), data);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn large_packets() {
+        let bus = VirtualBus::new();
+        let mut tester = IsotpCan::new(bus.endpoint(), 0x7E0, 0x7E8, Duration::from_millis(500));
+        let mut ecu = IsotpCan::new(bus.endpoint(), 0x7E8, 0x7E0, Duration::from_millis(500));
+        tester.max_packet_size = 0x10000;
+        ecu.max_packet_size = 0x10000;
+
+        let echo = thread::spawn(move || {
+            for _ in 0..2 {
+                let request = ecu.read_isotp().unwrap();
+                ecu.write_isotp(&request).unwrap();
+            }
+            // Peers without 32-bit lengths reject the packet
+            ecu.max_packet_size = MAX_SHORT_PACKET_SIZE;
+            assert!(matches!(
+                ecu.read_isotp(),
+                Err(IsotpError::TooLong {
+                    length: 5000,
+                    max: MAX_SHORT_PACKET_SIZE
+                })
+            ));
+        });
+        for &len in &[4096, 0x10000] {
+            let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            assert_eq!(tester.request_isotp(&data).unwrap(), data);
+        }
+        assert!(matches!(
+            tester.write_isotp(&[0; 5000]),
+            Err(IsotpError::Overflow)
+        ));
+        echo.join().unwrap();
+
+        assert!(matches!(
+            tester.write_isotp(&vec![0; 0x10001]),
+            Err(IsotpError::TooLong {
+                length: 0x10001,
+                max: 0x10000
+            })
+        ));
+    }
+
+    #[test]
+    fn extended_addressing() {
+        let bus = VirtualBus::new();
+        let mut tester = IsotpCan::new(bus.endpoint(), 0x6F1, 0x612, Duration::from_millis(500))
+            .with_addressing(AddressingMode::Extended {
+                target: 0x12,
+                source: 0xF1,
+            });
+        let mut ecu = IsotpCan::new(bus.endpoint(), 0x612, 0x6F1, Duration::from_millis(500))
+            .with_addressing(AddressingMode::Extended {
+                target: 0xF1,
+                source: 0x12,
+            });
+        tester.max_packet_size = 0x2000;
+        ecu.max_packet_size = 0x2000;
+        // Another ECU listening on the same id
+        let other = IsotpCan::new(bus.endpoint(), 0x613, 0x6F1, Duration::from_millis(50))
+            .with_addressing(AddressingMode::Extended {
+                target: 0xF1,
+                source: 0x13,
+            });
+
+        let lengths = [1, 6, 7, 12, 13, 100, 0x1000];
+        let echo = thread::spawn(move || {
+            for _ in 0..lengths.len() {
+                let request = ecu.read_isotp().unwrap();
+                ecu.write_isotp(&request).unwrap();
+            }
+        });
+        for &len in &lengths {
+            let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            assert_eq!(tester.request_isotp(&data).unwrap(), data);
+        }
+        echo.join().unwrap();
+        assert!(other.read_isotp().unwrap_err().is_timeout());
+
+        // Mixed addressing on 11-bit ids
+        let mixed = AddressingMode::Mixed {
+            address_extension: 0x55,
+        };
+        let tester = IsotpCan::new(bus.endpoint(), 0x7E0, 0x7E8, Duration::from_millis(500))
+            .with_addressing(mixed);
+        let ecu = IsotpCan::new(bus.endpoint(), 0x7E8, 0x7E0, Duration::from_millis(500))
+            .with_addressing(mixed);
+        let echo = thread::spawn(move || {
+            // Frames with another address extension are ignored
+            bus.endpoint().write(0x7E8, &[0x56, 0x01, 0xFF]).unwrap();
+            let request = ecu.read_isotp().unwrap();
+            ecu.write_isotp(&request).unwrap();
+        });
+        let data: Vec<u8> = (0..50).collect();
+        assert_eq!(tester.request_isotp(&data).unwrap(), data);
+        echo.join().unwrap();
+    }
+
+    #[test]
+    fn multi_frame() {
+        let bus = VirtualBus::new();
+        let tester = IsotpCan::new(bus.endpoint(), 0x7E0, 0x7E8, Duration::from_millis(500));
+        let ecu = IsotpCan::new(bus.endpoint(), 0x7E8, 0x7E0, Duration::from_millis(500));
+
+        let echo = thread::spawn(move || {
+            for _ in 0..4 {
+                let request = ecu.read_isotp().unwrap();
+                ecu.write_isotp(&request).unwrap();
+            }
+        });
+        for &len in &[8, 13, 14, 4095] {
+            let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            assert_eq!(tester.request_isotp(&data).unwrap(), data);
+        }
+        echo.join().unwrap();
+
+        assert!(matches!(
+            tester.write_isotp(&[0; 4096]),
+            Err(IsotpError::TooLong {
+                length: 4096,
+                max: 4095
+            })
+        ));
+        assert!(matches!(
+            tester.write_isotp(&[]),
+            Err(IsotpError::InvalidLength)
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "socketcan-datalink")]
     fn isotp() {
-        let can = CANSocket::open("test")?;
-        can.write_isotp_frame("test", 0x170);
+        let can = CANSocket::open("vcan0").unwrap();
+        let isotp = IsotpCan::new(can, 0x7E0, 0x7E8, Duration::from_millis(100));
+        isotp.write_isotp(b"test").unwrap();
+    }
+
+    #[test]
+    fn single_frame() {
+        let bus = VirtualBus::new();
+        let tester = IsotpCan::new(bus.endpoint(), 0x7E0, 0x7E8, Duration::from_millis(100));
+        let ecu = IsotpCan::new(bus.endpoint(), 0x7E8, 0x7E0, Duration::from_millis(100));
+
+        tester.write_isotp(&[0x10, 0x87]).unwrap();
+        assert_eq!(ecu.read_isotp().unwrap(), vec![0x10, 0x87]);
+        ecu.write_isotp(&[0x50, 0x87]).unwrap();
+        assert_eq!(tester.read_isotp().unwrap(), vec![0x50, 0x87]);
+    }
+
+    #[test]
+    fn ignores_other_ids() {
+        let bus = VirtualBus::new();
+        let tester = IsotpCan::new(bus.endpoint(), 0x7E0, 0x7E8, Duration::from_millis(100));
+        let other = bus.endpoint();
+
+        other.write(0x7E9, &[0x02, 0x50, 0x01]).unwrap();
+        other.write(0x7E8, &[0x02, 0x50, 0x87]).unwrap();
+        assert_eq!(tester.read_isotp().unwrap(), vec![0x50, 0x87]);
+    }
+
+    #[test]
+    fn read_timeout() {
+        let bus = VirtualBus::new();
+        let tester = IsotpCan::new(bus.endpoint(), 0x7E0, 0x7E8, Duration::from_millis(20));
+        assert!(tester.read_isotp().unwrap_err().is_timeout());
+
+        // Frames from other ids do not extend the timeout
+        let other = bus.endpoint();
+        let start = Instant::now();
+        let writer = thread::spawn(move || {
+            for _ in 0..50 {
+                other.write(0x7E9, &[0x02, 0x50, 0x01]).unwrap();
+                thread::sleep(Duration::from_millis(10));
+            }
+        });
+        assert!(tester
+            .read_isotp_timeout(Duration::from_millis(30))
+            .unwrap_err()
+            .is_timeout());
+        // The writer keeps sending for 500 ms
+        assert!(start.elapsed() < Duration::from_millis(300));
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn physical_request_ids() {
+        assert_eq!(physical_request_id(0x7E8), Some(0x7E0));
+        assert_eq!(physical_request_id(0x7EF), Some(0x7E7));
+        assert_eq!(physical_request_id(0x7DF), None);
+        assert_eq!(physical_request_id(0x18DA_F110), Some(0x18DA_10F1));
+        assert_eq!(physical_request_id(0x18DB_33F1), None);
+    }
+
+    #[test]
+    fn functional_request() {
+        let bus = VirtualBus::new();
+        let tester = IsotpCan::new(bus.endpoint(), 0x7E0, 0x7E8, Duration::from_millis(100))
+            .with_functional_id(OBD_FUNCTIONAL_ID);
+
+        // Each ECU waits for the functional request before responding
+        let ecus: Vec<_> = [(0x7E0, 0x7E8), (0x7E1, 0x7E9)]
+            .iter()
+            .map(|&(request_id, response_id)| {
+                let ecu = IsotpCan::new(
+                    bus.endpoint(),
+                    response_id,
+                    request_id,
+                    Duration::from_millis(500),
+                );
+                thread::spawn(move || {
+                    let msg = ecu.can.read(Duration::from_secs(1)).unwrap();
+                    assert_eq!(msg.id, OBD_FUNCTIONAL_ID);
+                    assert_eq!(&msg.data[..3], &[0x02, 0x09, 0x02]);
+                    if response_id == 0x7E8 {
+                        ecu.write_isotp(&[0x49, 0x02, 0x01]).unwrap();
+                    } else {
+                        let mut vin = vec![0x49, 0x02, 0x01];
+                        vin.extend_from_slice(b"JM1GG12L561234567");
+                        ecu.write_isotp(&vin).unwrap();
+                    }
+                })
+            })
+            .collect();
+        // Let the ECUs start listening
+        thread::sleep(Duration::from_millis(20));
+
+        let responses = tester
+            .request_isotp_functional(&[0x09, 0x02], Duration::from_millis(200))
+            .unwrap();
+        for ecu in ecus {
+            ecu.join().unwrap();
+        }
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[&0x7E8], vec![0x49, 0x02, 0x01]);
+        assert_eq!(&responses[&0x7E9][..3], &[0x49, 0x02, 0x01]);
+        assert_eq!(&responses[&0x7E9][3..], b"JM1GG12L561234567");
+
+        assert!(matches!(
+            tester.write_isotp_functional(&[0; 8]),
+            Err(IsotpError::TooLong { length: 8, max: 7 })
+        ));
+        let physical = IsotpCan::new(bus.endpoint(), 0x7E0, 0x7E8, Duration::from_millis(100));
+        assert!(physical.write_isotp_functional(&[0x3E, 0x80]).is_err());
+    }
+
+    #[test]
+    fn functional_request_parameters() {
+        let bus = VirtualBus::new();
+        let mut tester = IsotpCan::new(bus.endpoint(), 0x7A0, 0x7A8, Duration::from_millis(100))
+            .with_functional_id(0x700);
+        tester.block_size = 1;
+        tester.max_packet_size = 20;
+
+        // ECUs on non-standard ids, and one on a standard id
+        let ecus: Vec<_> = [(0x7A0, 0x7A8, 20), (0x7B0, 0x7B8, 21), (0x7E0, 0x7E8, 3)]
+            .iter()
+            .map(|&(request_id, response_id, length)| {
+                let ecu = IsotpCan::new(
+                    bus.endpoint(),
+                    response_id,
+                    request_id,
+                    Duration::from_millis(500),
+                );
+                thread::spawn(move || {
+                    let msg = ecu.can.read(Duration::from_secs(1)).unwrap();
+                    assert_eq!(msg.id, 0x700);
+                    ecu.write_isotp(&(0..length).collect::<Vec<u8>>())
+                })
+            })
+            .collect();
+        // Let the ECUs start listening
+        thread::sleep(Duration::from_millis(20));
+
+        let responses = tester
+            .request_isotp_functional_from(&[0x09, 0x02], Duration::from_millis(200), |id| match id
+            {
+                0x7A8 | 0x7B8 => Some(id - 8),
+                _ => None,
+            })
+            .unwrap();
+        let results: Vec<_> = ecus.into_iter().map(|ecu| ecu.join().unwrap()).collect();
+        // One flow control frame per consecutive frame
+        assert!(results[0].is_ok());
+        // Longer than max_packet_size
+        assert!(matches!(results[1], Err(IsotpError::Overflow)));
+        assert!(results[2].is_ok());
+
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[&0x7A8], (0..20).collect::<Vec<u8>>());
     }
 }
@@ -1,3 +1,7 @@
 pub mod can;
+pub mod dtc;
 pub mod isotp;
+pub mod scan;
+pub mod security;
+pub mod session;
 pub mod uds;
@@ -0,0 +1,230 @@
+use std::convert::TryFrom;
+use std::io;
+use std::time::{Duration, Instant};
+
+use crate::datalink::can::{Can, Message};
+use crate::datalink::isotp::{physical_request_id, AddressingMode, Frame, IsotpCan};
+use crate::datalink::uds::{UDS_REQ_READDATABYID, UDS_REQ_TESTERPRESENT};
+
+/// First 11-bit physical request id probed by [`standard_request_ids`]
+pub const REQUEST_ID_FIRST: u32 = 0x7E0;
+
+/// Last 11-bit physical request id probed by [`standard_request_ids`]
+pub const REQUEST_ID_LAST: u32 = 0x7E7;
+
+/// Tester address used in 29-bit normal fixed request ids (0x18DAxxF1)
+pub const TESTER_ADDRESS: u8 = 0xF1;
+
+/// Requests sent by [`scan`], in order: TesterPresent and ReadDataByIdentifier
+/// of the VIN (0xF190). Neither changes the state of the ECU.
+const PROBES: [&[u8]; 2] = [
+    &[UDS_REQ_TESTERPRESENT, 0x00],
+    &[UDS_REQ_READDATABYID, 0xF1, 0x90],
+];
+
+/// Request and response ids of an ECU found by [`scan`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct EcuAddress {
+    /// Id the tester sends requests on
+    pub request_id: u32,
+
+    /// Id the ECU responds on
+    pub response_id: u32,
+}
+
+impl EcuAddress {
+    /// Creates an ISO-TP stack that talks to the ECU.
+    pub fn isotp<C: Can>(&self, can: C, timeout: Duration) -> IsotpCan<C> {
+        IsotpCan::new(can, self.request_id, self.response_id, timeout)
+    }
+}
+
+/// Returns the standard OBD/UDS physical request ids: 0x7E0-0x7E7 followed by
+/// the 29-bit normal fixed ids 0x18DA00F1-0x18DAFFF1.
+pub fn standard_request_ids() -> Vec<u32> {
+    (REQUEST_ID_FIRST..=REQUEST_ID_LAST)
+        .chain((0..=0xFF).map(|ecu: u32| 0x18DA_0000 | (ecu << 8) | TESTER_ADDRESS as u32))
+        .collect()
+}
+
+/// Probes each of `request_ids` with TesterPresent, falling back to
+/// ReadDataByIdentifier (VIN) for ECUs that do not answer TesterPresent.
+/// Waits at most `timeout` for each response. Returns the ECUs that
+/// responded, positively or negatively, in the order they were probed.
+///
+/// Only one ECU is probed at a time, so scanning all
+/// [`standard_request_ids`] takes up to `2 * 264 * timeout`.
+///
+/// # Example
+/// ```no_run
+/// # use std::time::Duration;
+/// # use overboost::datalink::can::Can;
+/// # use overboost::datalink::scan::{scan, standard_request_ids};
+/// # fn find<C: Can>(can: &C) -> std::io::Result<()> {
+/// for ecu in scan(can, &standard_request_ids(), Duration::from_millis(50))? {
+///     println!("{:X} -> {:X}", ecu.request_id, ecu.response_id);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub fn scan<C: Can + ?Sized>(
+    can: &C,
+    request_ids: &[u32],
+    timeout: Duration,
+) -> io::Result<Vec<EcuAddress>> {
+    let mut found = Vec::new();
+    for &request_id in request_ids {
+        for probe in &PROBES {
+            if let Some(response_id) = probe_id(can, request_id, probe, timeout)? {
+                found.push(EcuAddress {
+                    request_id,
+                    response_id,
+                });
+                break;
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// Sends `request` as a single frame on `request_id` and returns the id of the
+/// first response to it.
+fn probe_id<C: Can + ?Sized>(
+    can: &C,
+    request_id: u32,
+    request: &[u8],
+    timeout: Duration,
+) -> io::Result<Option<u32>> {
+    let msg = Frame::single(request, AddressingMode::Normal)
+        .and_then(|frame| frame.as_can_message(request_id))
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    can.write(msg.id, &msg.data[..msg.len as usize])?;
+
+    let start_time = Instant::now();
+    loop {
+        let elapsed = start_time.elapsed();
+        if elapsed >= timeout {
+            return Ok(None);
+        }
+        let msg = match can.read(timeout - elapsed) {
+            Ok(msg) => msg,
+            Err(err)
+                if err.kind() == io::ErrorKind::TimedOut
+                    || err.kind() == io::ErrorKind::WouldBlock =>
+            {
+                return Ok(None)
+            }
+            Err(err) => return Err(err),
+        };
+        if msg.id != request_id && is_response(&msg, request_id, request[0]) {
+            return Ok(Some(msg.id));
+        }
+    }
+}
+
+/// Returns true if `msg` is a positive or negative response to service `sid`.
+/// Positive responses may start with a first frame. Responses on standard ids
+/// that belong to another request id are ignored, as they are late responses
+/// to an earlier probe.
+fn is_response(msg: &Message, request_id: u32, sid: u8) -> bool {
+    if physical_request_id(msg.id).is_some_and(|id| id != request_id) {
+        return false;
+    }
+    match Frame::try_from(msg.clone()) {
+        Ok(frame @ Frame::Single { .. }) => match frame.data() {
+            [response, ..] if *response == sid + 0x40 => true,
+            [0x7F, nrc_sid, _] => *nrc_sid == sid,
+            _ => false,
+        },
+        Ok(frame @ Frame::First { .. }) => frame.data()[0] == sid + 0x40,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    use crate::datalink::can::VirtualBus;
+    use crate::datalink::isotp::Isotp;
+    use crate::datalink::security::MazdaAlgorithm;
+    use crate::simulator::SimulatedEcu;
+    use crate::Rom;
+
+    use super::*;
+
+    #[test]
+    fn request_ids() {
+        let ids = standard_request_ids();
+        assert_eq!(ids.len(), 8 + 256);
+        assert_eq!(&ids[..2], &[0x7E0, 0x7E1]);
+        assert_eq!(ids[8], 0x18DA_00F1);
+        assert_eq!(ids[8 + 0x10], 0x18DA_10F1);
+    }
+
+    #[test]
+    fn scan_bus() {
+        let bus = VirtualBus::new();
+        let stop = Arc::new(AtomicBool::new(false));
+
+        // 11-bit ECU that answers TesterPresent
+        let engine = {
+            let isotp = IsotpCan::new(bus.endpoint(), 0x7E8, 0x7E0, Duration::from_millis(10));
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let mut ecu =
+                    SimulatedEcu::new(Rom::from(vec![0; 16]), Box::new(MazdaAlgorithm::default()));
+                ecu.serve(&isotp, &stop).unwrap()
+            })
+        };
+
+        // 29-bit ECU that only answers ReadDataByIdentifier with a VIN that
+        // needs a multi-frame response
+        let body = {
+            let isotp = IsotpCan::new(
+                bus.endpoint(),
+                0x18DA_F140,
+                0x18DA_40F1,
+                Duration::from_millis(10),
+            );
+            let stop = stop.clone();
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    match isotp.read_isotp() {
+                        Ok(request) if request == [UDS_REQ_READDATABYID, 0xF1, 0x90] => {
+                            // The scanner sends no flow control, so the
+                            // transfer times out after the first frame
+                            let mut response = vec![0x62, 0xF1, 0x90];
+                            response.extend_from_slice(b"JM1GG12L761000000");
+                            let _ = isotp.write_isotp(&response);
+                        }
+                        _ => {}
+                    }
+                }
+            })
+        };
+
+        let tester = bus.endpoint();
+        let request_ids = [0x7E0, 0x7E1, 0x18DA_10F1, 0x18DA_40F1];
+        let found = scan(&tester, &request_ids, Duration::from_millis(30)).unwrap();
+        assert_eq!(
+            found,
+            vec![
+                EcuAddress {
+                    request_id: 0x7E0,
+                    response_id: 0x7E8,
+                },
+                EcuAddress {
+                    request_id: 0x18DA_40F1,
+                    response_id: 0x18DA_F140,
+                },
+            ]
+        );
+
+        stop.store(true, Ordering::Relaxed);
+        engine.join().unwrap();
+        body.join().unwrap();
+    }
+}
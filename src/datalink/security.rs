@@ -0,0 +1,83 @@
+/// Computes SecurityAccess keys from seeds sent by the ECU.
+pub trait SecurityAlgorithm {
+    /// Returns the key for `seed` at security `level`, or `None` if the seed is
+    /// not valid for the algorithm.
+    fn compute_key(&self, level: u8, seed: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// Seed/key algorithm used by Mazda (and Ford) ECUs. The 3-byte seed and a
+/// 5-byte secret are shifted through a 24-bit LFSR to produce a 3-byte key.
+pub struct MazdaAlgorithm {
+    secret: [u8; 5],
+}
+
+impl MazdaAlgorithm {
+    pub fn new(secret: [u8; 5]) -> MazdaAlgorithm {
+        MazdaAlgorithm { secret }
+    }
+}
+
+impl Default for MazdaAlgorithm {
+    /// Returns the algorithm with the `MazdA` secret used by most Mazda ECUs.
+    fn default() -> MazdaAlgorithm {
+        MazdaAlgorithm::new(*b"MazdA")
+    }
+}
+
+impl SecurityAlgorithm for MazdaAlgorithm {
+    /// # Example
+    /// ```
+    /// use overboost::datalink::security::{MazdaAlgorithm, SecurityAlgorithm};
+    /// let key = MazdaAlgorithm::default().compute_key(1, &[0x12, 0x34, 0x56]);
+    /// assert_eq!(key, Some(vec![0x86, 0xCA, 0x06]));
+    /// ```
+    fn compute_key(&self, _level: u8, seed: &[u8]) -> Option<Vec<u8>> {
+        if seed.len() != 3 {
+            return None;
+        }
+
+        let mut state: u32 = 0xC5_41A9;
+        for byte in seed.iter().chain(self.secret.iter()) {
+            for bit in 0..8 {
+                let input = ((byte >> bit) & 1) as u32 ^ (state & 1);
+                state = (state >> 1) | (input << 23);
+                if input == 1 {
+                    // Taps at bits 3, 5, 12, 15 and 20
+                    state ^= 0x10_9028;
+                }
+            }
+        }
+
+        Some(vec![
+            (state >> 4) as u8,
+            (((state >> 20) & 0x0F) | ((state >> 8) & 0xF0)) as u8,
+            (((state >> 16) & 0x0F) | ((state << 4) & 0xF0)) as u8,
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mazda_key() {
+        let algorithm = MazdaAlgorithm::default();
+        let vectors: &[([u8; 3], [u8; 3])] = &[
+            ([0x12, 0x34, 0x56], [0x86, 0xCA, 0x06]),
+            ([0x00, 0x00, 0x01], [0x00, 0xA0, 0x36]),
+            ([0xAB, 0xCD, 0xEF], [0x0E, 0xCE, 0x2C]),
+            ([0xFF, 0xFF, 0xFF], [0x2A, 0x33, 0xA2]),
+        ];
+        for (seed, key) in vectors {
+            assert_eq!(algorithm.compute_key(1, seed).unwrap(), key);
+        }
+    }
+
+    #[test]
+    fn mazda_invalid_seed() {
+        let algorithm = MazdaAlgorithm::default();
+        assert_eq!(algorithm.compute_key(1, &[0x12, 0x34]), None);
+        assert_eq!(algorithm.compute_key(1, &[0x12, 0x34, 0x56, 0x78]), None);
+    }
+}
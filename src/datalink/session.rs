@@ -0,0 +1,187 @@
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::datalink::uds::{UdsError, UdsInterface};
+
+/// Default diagnostic session entered when a [`SessionGuard`] is dropped
+pub const DEFAULT_SESSION: u8 = 0x01;
+
+/// Keeps a non-default diagnostic session open.
+///
+/// While the guard is held, TesterPresent is sent from a background thread
+/// whenever no request was sent for `interval`. The guard owns the interface,
+/// so every request goes through it and is never interleaved with
+/// TesterPresent. Dropping the guard returns the ECU to the default session.
+///
+/// # Example
+/// ```no_run
+/// # use std::time::Duration;
+/// # use overboost::datalink::session::SessionGuard;
+/// # use overboost::datalink::uds::{UdsError, UdsInterface};
+/// # fn tune<U: UdsInterface + Send + Sync + 'static>(uds: U) -> Result<(), UdsError> {
+/// let session = SessionGuard::new(uds, 0x87, Duration::from_secs(2))?;
+/// let vin = session.read_data_by_identifier(0xF190)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct SessionGuard<U: UdsInterface + Send + Sync + 'static> {
+    uds: Arc<U>,
+    /// Time of the last request. Held while a request is in progress.
+    last_request: Arc<Mutex<Instant>>,
+    parameters: Vec<u8>,
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl<U: UdsInterface + Send + Sync + 'static> SessionGuard<U> {
+    /// Opens diagnostic session `session` and starts sending TesterPresent
+    /// after `interval` of inactivity. `interval` should be shorter than the
+    /// ECU's S3 timeout, which is 5 seconds by default.
+    pub fn new(uds: U, session: u8, interval: Duration) -> Result<SessionGuard<U>, UdsError> {
+        let parameters = uds.request_session(session)?;
+        let uds = Arc::new(uds);
+        let last_request = Arc::new(Mutex::new(Instant::now()));
+        let (stop, stopped) = mpsc::channel();
+
+        let thread = {
+            let uds = uds.clone();
+            let last_request = last_request.clone();
+            thread::spawn(move || {
+                let mut wait = interval;
+                loop {
+                    match stopped.recv_timeout(wait) {
+                        Err(RecvTimeoutError::Timeout) => {}
+                        _ => return,
+                    }
+                    let mut last_request = lock(&last_request);
+                    let idle = last_request.elapsed();
+                    if idle >= interval {
+                        // Errors are ignored; the next request will report a
+                        // lost session.
+                        let _ = uds.tester_present();
+                        *last_request = Instant::now();
+                        wait = interval;
+                    } else {
+                        wait = interval - idle;
+                    }
+                }
+            })
+        };
+
+        Ok(SessionGuard {
+            uds,
+            last_request,
+            parameters,
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+
+    /// Returns the sessionParameterRecord sent by the ECU when the session was opened.
+    pub fn parameters(&self) -> &[u8] {
+        &self.parameters
+    }
+}
+
+impl<U: UdsInterface + Send + Sync + 'static> UdsInterface for SessionGuard<U> {
+    fn request(&self, request_sid: u8, data: &[u8]) -> Result<Vec<u8>, UdsError> {
+        let mut last_request = lock(&self.last_request);
+        let res = self.uds.request(request_sid, data);
+        *last_request = Instant::now();
+        res
+    }
+
+    fn send(&self, request_sid: u8, data: &[u8]) -> Result<(), UdsError> {
+        let mut last_request = lock(&self.last_request);
+        let res = self.uds.send(request_sid, data);
+        *last_request = Instant::now();
+        res
+    }
+
+    fn send_functional(&self, request_sid: u8, data: &[u8]) -> Result<(), UdsError> {
+        let _guard = lock(&self.last_request);
+        self.uds.send_functional(request_sid, data)
+    }
+}
+
+impl<U: UdsInterface + Send + Sync + 'static> Drop for SessionGuard<U> {
+    fn drop(&mut self) {
+        // Dropping the sender wakes up and stops the thread
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        let _ = self.uds.request_session(DEFAULT_SESSION);
+    }
+}
+
+/// Locks the time of the last request. The time stays valid if a request
+/// panicked while the lock was held.
+fn lock(last_request: &Mutex<Instant>) -> MutexGuard<'_, Instant> {
+    last_request.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use crate::datalink::can::VirtualBus;
+    use crate::datalink::isotp::IsotpCan;
+    use crate::datalink::security::MazdaAlgorithm;
+    use crate::datalink::uds::{IsotpUds, UdsTiming};
+    use crate::simulator::SimulatedEcu;
+    use crate::Rom;
+
+    use super::*;
+
+    /// Returns the active session reported by the ECU.
+    fn active_session<U: UdsInterface + ?Sized>(uds: &U) -> u8 {
+        uds.read_data_by_identifier(0xF186).unwrap()[0]
+    }
+
+    #[test]
+    fn session_guard() {
+        let bus = VirtualBus::new();
+        let server = IsotpCan::new(bus.endpoint(), 0x7E8, 0x7E0, Duration::from_millis(10));
+        let mut ecu =
+            SimulatedEcu::new(Rom::from(vec![0; 16]), Box::new(MazdaAlgorithm::default()));
+        ecu.set_s3_timeout(Duration::from_millis(150));
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let stop = stop.clone();
+            thread::spawn(move || ecu.serve(&server, &stop).unwrap())
+        };
+
+        let client = || {
+            IsotpUds::new(
+                IsotpCan::new(bus.endpoint(), 0x7E0, 0x7E8, Duration::from_secs(1)),
+                UdsTiming::default(),
+            )
+        };
+
+        // Without TesterPresent the session times out
+        let uds = client();
+        uds.request_session(0x87).unwrap();
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(active_session(&uds), DEFAULT_SESSION);
+        drop(uds);
+
+        let session = SessionGuard::new(client(), 0x87, Duration::from_millis(50)).unwrap();
+        assert_eq!(session.parameters(), &[0x00, 0x32, 0x01, 0xF4]);
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(active_session(&session), 0x87);
+        for _ in 0..10 {
+            // Requests are not interleaved with TesterPresent
+            assert_eq!(active_session(&session), 0x87);
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        drop(session);
+        assert_eq!(active_session(&client()), DEFAULT_SESSION);
+
+        stop.store(true, Ordering::Relaxed);
+        handle.join().unwrap();
+    }
+}
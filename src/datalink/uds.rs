@@ -1,10 +1,13 @@
-use std::io::Cursor;
+use std::cmp;
+use std::fmt;
 use std::result::Result;
+use std::time::{Duration, Instant};
 
-use byteordered::ByteOrdered;
 use thiserror::Error;
 
+use crate::datalink::dtc::{dtc_code, Dtc, DtcSnapshot};
 use crate::datalink::isotp::{Isotp, IsotpError};
+use crate::datalink::security::SecurityAlgorithm;
 
 pub struct Response {
     pub data: Vec<u8>,
@@ -17,19 +20,163 @@ pub const UDS_REQ_READMEM: u8 = 0x23;
 pub const UDS_REQ_REQUESTDOWNLOAD: u8 = 0x34;
 pub const UDS_REQ_REQUESTUPLOAD: u8 = 0x35;
 pub const UDS_REQ_TRANSFERDATA: u8 = 0x36;
+pub const UDS_REQ_TRANSFEREXIT: u8 = 0x37;
+pub const UDS_REQ_ROUTINECONTROL: u8 = 0x31;
 pub const UDS_REQ_READDATABYID: u8 = 0x22;
+pub const UDS_REQ_WRITEDATABYID: u8 = 0x2E;
+pub const UDS_REQ_WRITEMEM: u8 = 0x3D;
+pub const UDS_REQ_ECURESET: u8 = 0x11;
+pub const UDS_REQ_COMMUNICATIONCONTROL: u8 = 0x28;
+pub const UDS_REQ_CONTROLDTCSETTING: u8 = 0x85;
+pub const UDS_REQ_TESTERPRESENT: u8 = 0x3E;
+pub const UDS_REQ_CLEARDTC: u8 = 0x14;
+pub const UDS_REQ_READDTC: u8 = 0x19;
+
+// ReadDTCInformation sub-functions
+pub const UDS_DTC_NUMBER_BY_STATUS_MASK: u8 = 0x01;
+pub const UDS_DTC_BY_STATUS_MASK: u8 = 0x02;
+pub const UDS_DTC_SNAPSHOT_IDENTIFICATION: u8 = 0x03;
+pub const UDS_DTC_SNAPSHOT_BY_DTC_NUMBER: u8 = 0x04;
+
+// Routine identifiers defined by ISO 14229-1
+pub const UDS_ROUTINE_ERASE_MEMORY: u16 = 0xFF00;
+pub const UDS_ROUTINE_CHECK_PROGRAMMING_DEPENDENCIES: u16 = 0xFF01;
+
+/// Sub-function bit that asks the ECU not to send a positive response
+pub const UDS_SUPPRESS_POSITIVE_RESPONSE: u8 = 0x80;
 
 // Negative response codes
 // requestCorrectlyReceivedResponsePending
+#[deprecated(note = "use `NegativeResponseCode::ResponsePending`")]
 pub const UDS_NRES_RCRRP: u8 = 0x78;
 
+macro_rules! negative_response_codes {
+    ($($(#[$doc:meta])* $name:ident = $code:expr, $desc:expr;)*) => {
+        /// Negative response codes defined by ISO 14229-1. Codes that are
+        /// reserved or manufacturer specific are returned as `Other`.
+        #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+        pub enum NegativeResponseCode {
+            $($(#[$doc])* $name,)*
+            Other(u8),
+        }
+
+        impl From<u8> for NegativeResponseCode {
+            fn from(code: u8) -> NegativeResponseCode {
+                match code {
+                    $($code => NegativeResponseCode::$name,)*
+                    code => NegativeResponseCode::Other(code),
+                }
+            }
+        }
+
+        impl From<NegativeResponseCode> for u8 {
+            fn from(code: NegativeResponseCode) -> u8 {
+                match code {
+                    $(NegativeResponseCode::$name => $code,)*
+                    NegativeResponseCode::Other(code) => code,
+                }
+            }
+        }
+
+        impl NegativeResponseCode {
+            /// Returns the ISO 14229-1 name of the code.
+            pub fn description(self) -> &'static str {
+                match self {
+                    $(NegativeResponseCode::$name => $desc,)*
+                    NegativeResponseCode::Other(0xF0..=0xFE) => "vehicleManufacturerSpecific",
+                    NegativeResponseCode::Other(_) => "ISOSAEReserved",
+                }
+            }
+        }
+    };
+}
+
+negative_response_codes! {
+    GeneralReject = 0x10, "generalReject";
+    ServiceNotSupported = 0x11, "serviceNotSupported";
+    SubFunctionNotSupported = 0x12, "subFunctionNotSupported";
+    IncorrectMessageLength = 0x13, "incorrectMessageLengthOrInvalidFormat";
+    ResponseTooLong = 0x14, "responseTooLong";
+    BusyRepeatRequest = 0x21, "busyRepeatRequest";
+    ConditionsNotCorrect = 0x22, "conditionsNotCorrect";
+    RequestSequenceError = 0x24, "requestSequenceError";
+    NoResponseFromSubnetComponent = 0x25, "noResponseFromSubnetComponent";
+    FailurePreventsExecution = 0x26, "failurePreventsExecutionOfRequestedAction";
+    RequestOutOfRange = 0x31, "requestOutOfRange";
+    SecurityAccessDenied = 0x33, "securityAccessDenied";
+    AuthenticationRequired = 0x34, "authenticationRequired";
+    InvalidKey = 0x35, "invalidKey";
+    ExceededNumberOfAttempts = 0x36, "exceededNumberOfAttempts";
+    RequiredTimeDelayNotExpired = 0x37, "requiredTimeDelayNotExpired";
+    UploadDownloadNotAccepted = 0x70, "uploadDownloadNotAccepted";
+    TransferDataSuspended = 0x71, "transferDataSuspended";
+    GeneralProgrammingFailure = 0x72, "generalProgrammingFailure";
+    WrongBlockSequenceCounter = 0x73, "wrongBlockSequenceCounter";
+    /// The request was received and the final response will follow.
+    /// Handled by the transport and never returned as an error.
+    ResponsePending = 0x78, "requestCorrectlyReceivedResponsePending";
+    SubFunctionNotSupportedInActiveSession = 0x7E, "subFunctionNotSupportedInActiveSession";
+    ServiceNotSupportedInActiveSession = 0x7F, "serviceNotSupportedInActiveSession";
+    RpmTooHigh = 0x81, "rpmTooHigh";
+    RpmTooLow = 0x82, "rpmTooLow";
+    EngineIsRunning = 0x83, "engineIsRunning";
+    EngineIsNotRunning = 0x84, "engineIsNotRunning";
+    EngineRunTimeTooLow = 0x85, "engineRunTimeTooLow";
+    TemperatureTooHigh = 0x86, "temperatureTooHigh";
+    TemperatureTooLow = 0x87, "temperatureTooLow";
+    VehicleSpeedTooHigh = 0x88, "vehicleSpeedTooHigh";
+    VehicleSpeedTooLow = 0x89, "vehicleSpeedTooLow";
+    ThrottlePedalTooHigh = 0x8A, "throttle/PedalTooHigh";
+    ThrottlePedalTooLow = 0x8B, "throttle/PedalTooLow";
+    TransmissionRangeNotInNeutral = 0x8C, "transmissionRangeNotInNeutral";
+    TransmissionRangeNotInGear = 0x8D, "transmissionRangeNotInGear";
+    BrakeSwitchNotClosed = 0x8F, "brakeSwitch(es)NotClosed";
+    ShifterLeverNotInPark = 0x90, "shifterLeverNotInPark";
+    TorqueConverterClutchLocked = 0x91, "torqueConverterClutchLocked";
+    VoltageTooHigh = 0x92, "voltageTooHigh";
+    VoltageTooLow = 0x93, "voltageTooLow";
+    ResourceTemporarilyNotAvailable = 0x94, "resourceTemporarilyNotAvailable";
+}
+
+impl NegativeResponseCode {
+    /// Returns true if the ECU may accept the same request when it is sent
+    /// again later, e.g. after it finished a previous operation or after a
+    /// security delay expired.
+    pub fn is_retryable(self) -> bool {
+        matches!(
+            self,
+            NegativeResponseCode::BusyRepeatRequest
+                | NegativeResponseCode::ConditionsNotCorrect
+                | NegativeResponseCode::RequiredTimeDelayNotExpired
+                | NegativeResponseCode::ResourceTemporarilyNotAvailable
+        )
+    }
+
+    /// Returns true if the ECU expects a delay before the request is sent
+    /// again.
+    pub fn requires_delay(self) -> bool {
+        matches!(
+            self,
+            NegativeResponseCode::BusyRepeatRequest
+                | NegativeResponseCode::RequiredTimeDelayNotExpired
+        )
+    }
+}
+
+impl fmt::Display for NegativeResponseCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (0x{:02X})", self.description(), u8::from(*self))
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum UdsError {
     #[error(transparent)]
     Isotp(#[from] IsotpError),
 
-    #[error("negative UDS response: {0}")]
-    NegativeResponse(u8),
+    /// The ECU rejected request `sid` with `code`.
+    #[error("negative UDS response to service 0x{sid:02X}: {code}")]
+    NegativeResponse { sid: u8, code: NegativeResponseCode },
 
     #[error("empty UDS response")]
     EmptyResponse,
@@ -39,11 +186,234 @@ pub enum UdsError {
 
     #[error("invalid response data")]
     InvalidResponse,
+
+    /// Occurs when the security algorithm can not compute a key for the seed.
+    #[error("invalid security seed")]
+    InvalidSeed,
+
+    /// Occurs when an address or size does not fit the [`MemoryAddressFormat`].
+    #[error("address or size does not fit the memory address format")]
+    InvalidAddressFormat,
+
+    /// Occurs when a DTC or DTC group does not fit in 3 bytes.
+    #[error("DTC 0x{0:X} does not fit in 3 bytes")]
+    InvalidDtc(u32),
+
+    /// Occurs when requesting all snapshot records (0xFF) at once. Their
+    /// data can not be split without knowing the length of each identifier.
+    #[error("snapshot record number 0x{0:02X} is not supported")]
+    UnsupportedRecordNumber(u8),
+
+    /// Occurs when the interface can not send a request, e.g. a functional
+    /// request over an interface without a functional address.
+    #[error("request not supported by the interface")]
+    Unsupported,
+
+    /// The ECU did not respond within P2 or P2*, or the request deadline expired.
+    #[error("timed out waiting for UDS response")]
+    TimedOut,
+}
+
+/// resetType of ECUReset requests.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ResetType {
+    HardReset = 0x01,
+    KeyOffOnReset = 0x02,
+    SoftReset = 0x03,
+    EnableRapidPowerShutDown = 0x04,
+    DisableRapidPowerShutDown = 0x05,
+}
+
+/// controlType of CommunicationControl requests.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CommunicationControlType {
+    EnableRxAndTx = 0x00,
+    EnableRxAndDisableTx = 0x01,
+    DisableRxAndEnableTx = 0x02,
+    DisableRxAndTx = 0x03,
+}
+
+// communicationType of CommunicationControl requests
+pub const UDS_COMMUNICATION_NORMAL: u8 = 0x01;
+pub const UDS_COMMUNICATION_NETWORK_MANAGEMENT: u8 = 0x02;
+pub const UDS_COMMUNICATION_ALL: u8 = 0x03;
+
+/// DTCSettingType of ControlDTCSetting requests.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DtcSettingType {
+    On = 0x01,
+    Off = 0x02,
+}
+
+/// routineControlType of RoutineControl requests.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RoutineControlType {
+    Start = 0x01,
+    Stop = 0x02,
+    RequestResults = 0x03,
+}
+
+/// addressAndLengthFormatIdentifier of memory services: the number of bytes
+/// used to encode memoryAddress and memorySize.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MemoryAddressFormat {
+    address_bytes: u8,
+    size_bytes: u8,
+    alfid: bool,
+}
+
+impl MemoryAddressFormat {
+    /// Creates a format with `address_bytes` and `size_bytes` bytes. Returns
+    /// [`UdsError::InvalidAddressFormat`] unless both are between 1 and 4.
+    pub fn new(address_bytes: u8, size_bytes: u8) -> Result<MemoryAddressFormat, UdsError> {
+        if !(1..=4).contains(&address_bytes) || !(1..=4).contains(&size_bytes) {
+            return Err(UdsError::InvalidAddressFormat);
+        }
+        Ok(MemoryAddressFormat {
+            address_bytes,
+            size_bytes,
+            alfid: true,
+        })
+    }
+
+    /// Creates a format like [`new`](Self::new) whose requests omit the
+    /// addressAndLengthFormatIdentifier, as expected by ECUs that predate
+    /// ISO 14229.
+    pub fn without_alfid(
+        address_bytes: u8,
+        size_bytes: u8,
+    ) -> Result<MemoryAddressFormat, UdsError> {
+        Ok(MemoryAddressFormat {
+            alfid: false,
+            ..MemoryAddressFormat::new(address_bytes, size_bytes)?
+        })
+    }
+
+    pub fn address_bytes(&self) -> u8 {
+        self.address_bytes
+    }
+
+    pub fn size_bytes(&self) -> u8 {
+        self.size_bytes
+    }
+
+    /// Returns the addressAndLengthFormatIdentifier byte.
+    /// # Example
+    /// ```
+    /// use overboost::datalink::uds::MemoryAddressFormat;
+    /// assert_eq!(MemoryAddressFormat::new(3, 2).unwrap().alfid(), 0x23);
+    /// ```
+    pub fn alfid(&self) -> u8 {
+        (self.size_bytes << 4) | self.address_bytes
+    }
+
+    /// Returns true if requests start with the addressAndLengthFormatIdentifier.
+    pub fn has_alfid(&self) -> bool {
+        self.alfid
+    }
+
+    /// Appends the addressAndLengthFormatIdentifier, if any, `address` and
+    /// `size` to `request`.
+    pub fn encode(&self, address: u32, size: u32, request: &mut Vec<u8>) -> Result<(), UdsError> {
+        let fits = |value: u32, bytes: u8| bytes == 4 || value >> (bytes * 8) == 0;
+        if !fits(address, self.address_bytes) || !fits(size, self.size_bytes) {
+            return Err(UdsError::InvalidAddressFormat);
+        }
+        if self.alfid {
+            request.push(self.alfid());
+        }
+        request.extend_from_slice(&address.to_be_bytes()[4 - self.address_bytes as usize..]);
+        request.extend_from_slice(&size.to_be_bytes()[4 - self.size_bytes as usize..]);
+        Ok(())
+    }
+}
+
+impl Default for MemoryAddressFormat {
+    /// Returns the format with a 4-byte address and a 4-byte size.
+    fn default() -> MemoryAddressFormat {
+        MemoryAddressFormat {
+            address_bytes: 4,
+            size_bytes: 4,
+            alfid: true,
+        }
+    }
+}
+
+/// Client side UDS timing parameters.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct UdsTiming {
+    /// Time to wait for the first response to a request
+    pub p2: Duration,
+
+    /// Time to wait for the next response after a responsePending (0x78) response
+    pub p2_star: Duration,
+
+    /// Total time a request may take, including all responsePending responses.
+    /// `None` waits for as long as the ECU keeps sending responsePending.
+    pub deadline: Option<Duration>,
+}
+
+impl Default for UdsTiming {
+    /// Returns the ISO 14229-2 default server timings (P2 = 50 ms,
+    /// P2* = 5000 ms) with a margin for network delays, and a 60 s deadline.
+    fn default() -> UdsTiming {
+        UdsTiming {
+            p2: Duration::from_millis(150),
+            p2_star: Duration::from_millis(5100),
+            deadline: Some(Duration::from_secs(60)),
+        }
+    }
+}
+
+impl UdsError {
+    /// Returns the negative response code if the ECU rejected the request.
+    pub fn negative_response_code(&self) -> Option<NegativeResponseCode> {
+        match self {
+            UdsError::NegativeResponse { code, .. } => Some(*code),
+            _ => None,
+        }
+    }
+
+    /// Returns true if sending the request again may succeed. Transport
+    /// errors, timeouts and malformed responses are assumed to be transient;
+    /// negative responses are retryable if their code is.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            UdsError::NegativeResponse { code, .. } => code.is_retryable(),
+            UdsError::Isotp(_)
+            | UdsError::EmptyResponse
+            | UdsError::InvalidResponseId
+            | UdsError::InvalidResponse
+            | UdsError::TimedOut => true,
+            _ => false,
+        }
+    }
 }
 
 pub trait UdsInterface {
     fn request(&self, request_sid: u8, data: &[u8]) -> Result<Vec<u8>, UdsError>;
 
+    /// Sends a request without waiting for a response. Used for requests with
+    /// the suppressPosRspMsgIndicationBit set. The default implementation
+    /// sends the request with [`UdsInterface::request`] and discards the response.
+    fn send(&self, request_sid: u8, data: &[u8]) -> Result<(), UdsError> {
+        self.request(request_sid, data).map(|_| ())
+    }
+
+    /// Sends a request to all ECUs with functional addressing without
+    /// waiting for responses. The request must fit in a single frame. By
+    /// default, returns [`UdsError::Unsupported`].
+    fn send_functional(&self, request_sid: u8, data: &[u8]) -> Result<(), UdsError> {
+        let _ = (request_sid, data);
+        Err(UdsError::Unsupported)
+    }
+
+    /// Sends TesterPresent with a suppressed positive response to keep the
+    /// active diagnostic session open.
+    fn tester_present(&self) -> Result<(), UdsError> {
+        self.send(UDS_REQ_TESTERPRESENT, &[UDS_SUPPRESS_POSITIVE_RESPONSE])
+    }
+
     /// Sends a DiagnosticSessionControl request. Returns parameter record.
     fn request_session(&self, session_type: u8) -> Result<Vec<u8>, UdsError> {
         let mut response = self.request(UDS_REQ_SESSION, &[session_type])?;
@@ -59,14 +429,17 @@ pub trait UdsInterface {
         Ok(response)
     }
 
-    fn request_security_seed(&self) -> Result<Vec<u8>, UdsError> {
-        let mut response = self.request(UDS_REQ_SECURITY, &[1])?;
+    /// Sends a SecurityAccess requestSeed request for `level`. Returns the seed.
+    /// `level` is the odd requestSeed sub-function; the matching sendKey
+    /// sub-function is `level + 1`.
+    fn request_security_seed(&self, level: u8) -> Result<Vec<u8>, UdsError> {
+        let mut response = self.request(UDS_REQ_SECURITY, &[level])?;
 
         if response.is_empty() {
             return Err(UdsError::EmptyResponse);
         }
 
-        if response[0] != 1 {
+        if response[0] != level {
             return Err(UdsError::InvalidResponse);
         }
 
@@ -74,29 +447,103 @@ pub trait UdsInterface {
         Ok(response)
     }
 
-    fn request_security_key(&self, key: &[u8]) -> Result<(), UdsError> {
+    /// Sends a SecurityAccess sendKey request for `level`.
+    fn request_security_key(&self, level: u8, key: &[u8]) -> Result<(), UdsError> {
         let mut request = Vec::with_capacity(key.len() + 1);
-        request.push(2);
-        request.extend_from_slice(&key);
+        request.push(level.wrapping_add(1));
+        request.extend_from_slice(key);
 
         let _response = self.request(UDS_REQ_SECURITY, &request)?;
         Ok(())
     }
 
-    fn request_read_memory_address(&self, address: u32, length: u16) -> Result<Vec<u8>, UdsError> {
-        let mut request = [0; 6];
-        {
-            let mut buff = Cursor::new(&mut request as &mut [u8]);
-            let mut wt = ByteOrdered::be(&mut buff);
-            wt.write_u32(address).unwrap();
-            wt.write_u16(length).unwrap();
+    /// Unlocks security `level` by requesting a seed, computing the key with
+    /// `algorithm` and sending it. A seed of all zeros means the level is
+    /// already unlocked and no key is sent.
+    fn unlock(&self, level: u8, algorithm: &dyn SecurityAlgorithm) -> Result<(), UdsError> {
+        let seed = self.request_security_seed(level)?;
+        if seed.iter().all(|&b| b == 0) {
+            return Ok(());
         }
+
+        let key = algorithm
+            .compute_key(level, &seed)
+            .ok_or(UdsError::InvalidSeed)?;
+        self.request_security_key(level, &key)
+    }
+
+    /// Reads `length` bytes of memory at `address` (ReadMemoryByAddress).
+    fn request_read_memory_address(
+        &self,
+        format: MemoryAddressFormat,
+        address: u32,
+        length: u32,
+    ) -> Result<Vec<u8>, UdsError> {
+        let mut request = Vec::with_capacity(9);
+        format.encode(address, length, &mut request)?;
         self.request(UDS_REQ_READMEM, &request)
     }
 
+    /// Sends a RequestDownload request for `size` bytes at `address`.
+    /// Returns maxNumberOfBlockLength, the maximum length of a TransferData
+    /// request including the SID and blockSequenceCounter.
+    fn request_download(
+        &self,
+        data_format: u8,
+        format: MemoryAddressFormat,
+        address: u32,
+        size: u32,
+    ) -> Result<usize, UdsError> {
+        let mut request = vec![data_format];
+        format.encode(address, size, &mut request)?;
+        let response = self.request(UDS_REQ_REQUESTDOWNLOAD, &request)?;
+        parse_max_block_length(&response)
+    }
+
+    /// Sends a RequestUpload request for `size` bytes at `address`. Returns
+    /// maxNumberOfBlockLength, the maximum length of a TransferData response
+    /// including the SID and blockSequenceCounter.
+    fn request_upload(
+        &self,
+        data_format: u8,
+        format: MemoryAddressFormat,
+        address: u32,
+        size: u32,
+    ) -> Result<usize, UdsError> {
+        let mut request = vec![data_format];
+        format.encode(address, size, &mut request)?;
+        let response = self.request(UDS_REQ_REQUESTUPLOAD, &request)?;
+        parse_max_block_length(&response)
+    }
+
+    /// Sends a TransferData request. Returns transferResponseParameterRecord.
+    fn transfer_data(&self, sequence: u8, data: &[u8]) -> Result<Vec<u8>, UdsError> {
+        let mut request = Vec::with_capacity(data.len() + 1);
+        request.push(sequence);
+        request.extend_from_slice(data);
+
+        let mut response = self.request(UDS_REQ_TRANSFERDATA, &request)?;
+        if response.is_empty() {
+            return Err(UdsError::EmptyResponse);
+        }
+
+        if response[0] != sequence {
+            // Check blockSequenceCounter
+            return Err(UdsError::InvalidResponse);
+        }
+
+        response.remove(0);
+        Ok(response)
+    }
+
+    /// Sends a RequestTransferExit request. Returns transferResponseParameterRecord.
+    fn request_transfer_exit(&self) -> Result<Vec<u8>, UdsError> {
+        self.request(UDS_REQ_TRANSFEREXIT, &[])
+    }
+
     fn read_data_by_identifier(&self, id: u16) -> Result<Vec<u8>, UdsError> {
         let request = &[(id >> 8) as u8, (id & 0xFF) as u8];
-        let mut res = self.request(UDS_REQ_READDATABYID, request)?;
+        let res = self.request(UDS_REQ_READDATABYID, request)?;
         if res.len() < 2 {
             return Err(UdsError::InvalidResponse);
         }
@@ -107,39 +554,840 @@ pub trait UdsInterface {
         // Remove dataIdentifier
         Ok(res.into_iter().skip(2).collect())
     }
+
+    /// Sends an ECUReset request. Returns powerDownTime if the ECU sent one.
+    fn ecu_reset(&self, reset_type: ResetType) -> Result<Option<u8>, UdsError> {
+        let response = self.request(UDS_REQ_ECURESET, &[reset_type as u8])?;
+        match response[..] {
+            [t] if t == reset_type as u8 => Ok(None),
+            [t, power_down_time] if t == reset_type as u8 => Ok(Some(power_down_time)),
+            _ => Err(UdsError::InvalidResponse),
+        }
+    }
+
+    /// Sends an ECUReset request to all ECUs without waiting for responses.
+    fn ecu_reset_functional(&self, reset_type: ResetType) -> Result<(), UdsError> {
+        self.send_functional(
+            UDS_REQ_ECURESET,
+            &[reset_type as u8 | UDS_SUPPRESS_POSITIVE_RESPONSE],
+        )
+    }
+
+    /// Sends a CommunicationControl request. `communication_type` is one of
+    /// the `UDS_COMMUNICATION_*` constants.
+    fn communication_control(
+        &self,
+        control_type: CommunicationControlType,
+        communication_type: u8,
+    ) -> Result<(), UdsError> {
+        let response = self.request(
+            UDS_REQ_COMMUNICATIONCONTROL,
+            &[control_type as u8, communication_type],
+        )?;
+        if response != [control_type as u8] {
+            return Err(UdsError::InvalidResponse);
+        }
+        Ok(())
+    }
+
+    /// Sends a CommunicationControl request to all ECUs without waiting for
+    /// responses.
+    fn communication_control_functional(
+        &self,
+        control_type: CommunicationControlType,
+        communication_type: u8,
+    ) -> Result<(), UdsError> {
+        self.send_functional(
+            UDS_REQ_COMMUNICATIONCONTROL,
+            &[
+                control_type as u8 | UDS_SUPPRESS_POSITIVE_RESPONSE,
+                communication_type,
+            ],
+        )
+    }
+
+    /// Sends a ControlDTCSetting request.
+    fn control_dtc_setting(&self, setting: DtcSettingType) -> Result<(), UdsError> {
+        let response = self.request(UDS_REQ_CONTROLDTCSETTING, &[setting as u8])?;
+        if response != [setting as u8] {
+            return Err(UdsError::InvalidResponse);
+        }
+        Ok(())
+    }
+
+    /// Sends a ControlDTCSetting request to all ECUs without waiting for responses.
+    fn control_dtc_setting_functional(&self, setting: DtcSettingType) -> Result<(), UdsError> {
+        self.send_functional(
+            UDS_REQ_CONTROLDTCSETTING,
+            &[setting as u8 | UDS_SUPPRESS_POSITIVE_RESPONSE],
+        )
+    }
+
+    /// Writes `data` to data identifier `id`.
+    fn write_data_by_identifier(&self, id: u16, data: &[u8]) -> Result<(), UdsError> {
+        let mut request = Vec::with_capacity(data.len() + 2);
+        request.extend_from_slice(&id.to_be_bytes());
+        request.extend_from_slice(data);
+
+        let res = self.request(UDS_REQ_WRITEDATABYID, &request)?;
+        if res != request[..2] {
+            // Check dataIdentifier
+            return Err(UdsError::InvalidResponse);
+        }
+        Ok(())
+    }
+
+    /// Writes `data` to memory at `address` (WriteMemoryByAddress).
+    fn write_memory_by_address(
+        &self,
+        format: MemoryAddressFormat,
+        address: u32,
+        data: &[u8],
+    ) -> Result<(), UdsError> {
+        let mut request = Vec::with_capacity(data.len() + 9);
+        format.encode(address, data.len() as u32, &mut request)?;
+        let header_len = request.len();
+        request.extend_from_slice(data);
+
+        let res = self.request(UDS_REQ_WRITEMEM, &request)?;
+        if res != request[..header_len] {
+            // Check addressAndLengthFormatIdentifier, memoryAddress and memorySize
+            return Err(UdsError::InvalidResponse);
+        }
+        Ok(())
+    }
+
+    /// Sends a RoutineControl request for routine `id` with
+    /// routineControlOptionRecord `option`. Checks the echoed control type
+    /// and routine identifier and returns the routineStatusRecord.
+    fn routine_control(
+        &self,
+        control_type: RoutineControlType,
+        id: u16,
+        option: &[u8],
+    ) -> Result<Vec<u8>, UdsError> {
+        let mut request = Vec::with_capacity(option.len() + 3);
+        request.push(control_type as u8);
+        request.extend_from_slice(&id.to_be_bytes());
+        request.extend_from_slice(option);
+
+        let response = self.request(UDS_REQ_ROUTINECONTROL, &request)?;
+        if response.len() < 3 || response[..3] != request[..3] {
+            // Check routineControlType and routineIdentifier
+            return Err(UdsError::InvalidResponse);
+        }
+        Ok(response[3..].to_vec())
+    }
+
+    /// Starts routine `id`. Returns the routineStatusRecord.
+    fn start_routine(&self, id: u16, option: &[u8]) -> Result<Vec<u8>, UdsError> {
+        self.routine_control(RoutineControlType::Start, id, option)
+    }
+
+    /// Stops routine `id`. Returns the routineStatusRecord.
+    fn stop_routine(&self, id: u16, option: &[u8]) -> Result<Vec<u8>, UdsError> {
+        self.routine_control(RoutineControlType::Stop, id, option)
+    }
+
+    /// Requests the results of routine `id`. Returns the routineStatusRecord.
+    fn request_routine_results(&self, id: u16) -> Result<Vec<u8>, UdsError> {
+        self.routine_control(RoutineControlType::RequestResults, id, &[])
+    }
+
+    /// Sends a ReadDTCInformation request for `report_type` and checks the
+    /// echoed sub-function. Returns the response without the sub-function.
+    fn read_dtc_information(&self, report_type: u8, data: &[u8]) -> Result<Vec<u8>, UdsError> {
+        let mut request = Vec::with_capacity(data.len() + 1);
+        request.push(report_type);
+        request.extend_from_slice(data);
+
+        let mut response = self.request(UDS_REQ_READDTC, &request)?;
+        if response.is_empty() {
+            return Err(UdsError::EmptyResponse);
+        }
+        if response[0] != report_type {
+            return Err(UdsError::InvalidResponse);
+        }
+        response.remove(0);
+        Ok(response)
+    }
+
+    /// Returns the number of DTCs matching `status_mask`
+    /// (reportNumberOfDTCByStatusMask).
+    fn read_dtc_count(&self, status_mask: u8) -> Result<u16, UdsError> {
+        let response = self.read_dtc_information(UDS_DTC_NUMBER_BY_STATUS_MASK, &[status_mask])?;
+        // DTCStatusAvailabilityMask, DTCFormatIdentifier, DTCCount
+        if response.len() != 4 {
+            return Err(UdsError::InvalidResponse);
+        }
+        Ok(u16::from_be_bytes([response[2], response[3]]))
+    }
+
+    /// Returns the DTCs matching `status_mask` (reportDTCByStatusMask).
+    fn read_dtcs(&self, status_mask: u8) -> Result<Vec<Dtc>, UdsError> {
+        let response = self.read_dtc_information(UDS_DTC_BY_STATUS_MASK, &[status_mask])?;
+        // DTCStatusAvailabilityMask followed by DTC and status records
+        if response.is_empty() || (response.len() - 1) % 4 != 0 {
+            return Err(UdsError::InvalidResponse);
+        }
+        Ok(response[1..].chunks_exact(4).map(Dtc::parse).collect())
+    }
+
+    /// Returns the DTCs that have snapshot records and the record numbers
+    /// (reportDTCSnapshotIdentification).
+    fn read_dtc_snapshot_ids(&self) -> Result<Vec<(u32, u8)>, UdsError> {
+        let response = self.read_dtc_information(UDS_DTC_SNAPSHOT_IDENTIFICATION, &[])?;
+        if response.len() % 4 != 0 {
+            return Err(UdsError::InvalidResponse);
+        }
+        Ok(response
+            .chunks_exact(4)
+            .map(|record| (dtc_code(record), record[3]))
+            .collect())
+    }
+
+    /// Reads snapshot record `record_number` of `dtc`
+    /// (reportDTCSnapshotRecordByDTCNumber). Returns `None` if the ECU has
+    /// no such record. Record number 0xFF (all records) is rejected with
+    /// [`UdsError::UnsupportedRecordNumber`]; read the numbers returned by
+    /// [`read_dtc_snapshot_ids`](Self::read_dtc_snapshot_ids) one at a time.
+    fn read_dtc_snapshot(
+        &self,
+        dtc: u32,
+        record_number: u8,
+    ) -> Result<Option<DtcSnapshot>, UdsError> {
+        if dtc > 0xFF_FFFF {
+            return Err(UdsError::InvalidDtc(dtc));
+        }
+        if record_number == 0xFF {
+            return Err(UdsError::UnsupportedRecordNumber(record_number));
+        }
+        let dtc_bytes = &dtc.to_be_bytes()[1..];
+        let mut request = dtc_bytes.to_vec();
+        request.push(record_number);
+
+        let response = self.read_dtc_information(UDS_DTC_SNAPSHOT_BY_DTC_NUMBER, &request)?;
+        if response.len() < 4 || &response[..3] != dtc_bytes {
+            return Err(UdsError::InvalidResponse);
+        }
+        let dtc = Dtc::parse(&response);
+        match response.len() {
+            4 => Ok(None),
+            5 => Err(UdsError::InvalidResponse),
+            _ => {
+                if response[4] != record_number {
+                    return Err(UdsError::InvalidResponse);
+                }
+                Ok(Some(DtcSnapshot {
+                    dtc,
+                    record_number,
+                    identifiers: response[5],
+                    data: response[6..].to_vec(),
+                }))
+            }
+        }
+    }
+
+    /// Clears the DTCs in `group` (ClearDiagnosticInformation). Use
+    /// [`DTC_GROUP_ALL`](crate::datalink::dtc::DTC_GROUP_ALL) to clear all DTCs.
+    fn clear_dtcs(&self, group: u32) -> Result<(), UdsError> {
+        if group > 0xFF_FFFF {
+            return Err(UdsError::InvalidDtc(group));
+        }
+        self.request(UDS_REQ_CLEARDTC, &group.to_be_bytes()[1..])?;
+        Ok(())
+    }
+}
+
+/// Parses the lengthFormatIdentifier and maxNumberOfBlockLength of a
+/// RequestDownload or RequestUpload response.
+fn parse_max_block_length(response: &[u8]) -> Result<usize, UdsError> {
+    if response.is_empty() {
+        return Err(UdsError::EmptyResponse);
+    }
+
+    // lengthFormatIdentifier
+    let length = (response[0] >> 4) as usize;
+    if length == 0 || length > 8 || response.len() != length + 1 {
+        return Err(UdsError::InvalidResponse);
+    }
+    let max_block_length = response[1..]
+        .iter()
+        .fold(0_u64, |n, &b| (n << 8) | b as u64);
+    Ok(max_block_length as usize)
 }
 
+/// UDS client over an ISO-TP link with configurable timing.
+pub struct IsotpUds<I: Isotp> {
+    pub isotp: I,
+    pub timing: UdsTiming,
+}
+
+impl<I: Isotp> IsotpUds<I> {
+    pub fn new(isotp: I, timing: UdsTiming) -> IsotpUds<I> {
+        IsotpUds { isotp, timing }
+    }
+}
+
+impl<I: Isotp> UdsInterface for IsotpUds<I> {
+    fn request(&self, request_sid: u8, data: &[u8]) -> Result<Vec<u8>, UdsError> {
+        isotp_request(&self.isotp, &self.timing, request_sid, data)
+    }
+
+    fn send(&self, request_sid: u8, data: &[u8]) -> Result<(), UdsError> {
+        isotp_send(&self.isotp, request_sid, data)
+    }
+
+    fn send_functional(&self, request_sid: u8, data: &[u8]) -> Result<(), UdsError> {
+        isotp_send_functional(&self.isotp, request_sid, data)
+    }
+}
+
+/// Uses the default [`UdsTiming`].
 impl UdsInterface for dyn Isotp {
     fn request(&self, request_sid: u8, data: &[u8]) -> Result<Vec<u8>, UdsError> {
-        let mut v = Vec::new();
-        v.push(request_sid);
-        v.extend_from_slice(&data);
-
-        self.write_isotp(&v)?;
-        // Receive packets until we get a non-response-pending packet
-        loop {
-            let response = self.read_isotp()?;
-            if response.is_empty() {
-                return Err(UdsError::EmptyResponse);
-            }
-
-            if response[0] == 0x7F {
-                // Negative code
-                if response.len() > 1 {
-                    if response[1] == UDS_NRES_RCRRP {
-                        // Request correctly received, response pending
-                        continue;
-                    }
-                    return Err(UdsError::NegativeResponse(response[1]));
+        isotp_request(self, &UdsTiming::default(), request_sid, data)
+    }
+
+    fn send(&self, request_sid: u8, data: &[u8]) -> Result<(), UdsError> {
+        isotp_send(self, request_sid, data)
+    }
+
+    fn send_functional(&self, request_sid: u8, data: &[u8]) -> Result<(), UdsError> {
+        isotp_send_functional(self, request_sid, data)
+    }
+}
+
+fn isotp_send<I: Isotp + ?Sized>(isotp: &I, request_sid: u8, data: &[u8]) -> Result<(), UdsError> {
+    let mut v = Vec::with_capacity(data.len() + 1);
+    v.push(request_sid);
+    v.extend_from_slice(data);
+    isotp.write_isotp(&v)?;
+    Ok(())
+}
+
+fn isotp_send_functional<I: Isotp + ?Sized>(
+    isotp: &I,
+    request_sid: u8,
+    data: &[u8],
+) -> Result<(), UdsError> {
+    let mut v = Vec::with_capacity(data.len() + 1);
+    v.push(request_sid);
+    v.extend_from_slice(data);
+    match isotp.write_isotp_functional(&v) {
+        Err(IsotpError::Unsupported) => Err(UdsError::Unsupported),
+        res => Ok(res?),
+    }
+}
+
+/// Sends a request and waits for the final response according to `timing`.
+fn isotp_request<I: Isotp + ?Sized>(
+    isotp: &I,
+    timing: &UdsTiming,
+    request_sid: u8,
+    data: &[u8],
+) -> Result<Vec<u8>, UdsError> {
+    isotp_send(isotp, request_sid, data)?;
+    let start = Instant::now();
+    let mut timeout = timing.p2;
+    // Receive packets until we get a non-response-pending packet
+    loop {
+        if let Some(deadline) = timing.deadline {
+            let remaining = deadline
+                .checked_sub(start.elapsed())
+                .ok_or(UdsError::TimedOut)?;
+            timeout = cmp::min(timeout, remaining);
+        }
+        let response = match isotp.read_isotp_timeout(timeout) {
+            Err(err) if err.is_timeout() => return Err(UdsError::TimedOut),
+            res => res?,
+        };
+        if response.is_empty() {
+            return Err(UdsError::EmptyResponse);
+        }
+
+        if response[0] == 0x7F {
+            // Negative code: [0x7F, requestSid, responseCode]
+            if response.len() != 3 {
+                return Err(UdsError::InvalidResponse);
+            }
+            if response[1] == UDS_REQ_TESTERPRESENT && request_sid != UDS_REQ_TESTERPRESENT {
+                // Late negative response to a suppressed TesterPresent
+                continue;
+            }
+            if response[1] != request_sid {
+                return Err(UdsError::InvalidResponseId);
+            }
+            let code = NegativeResponseCode::from(response[2]);
+            if code == NegativeResponseCode::ResponsePending {
+                // Request correctly received, response pending
+                timeout = timing.p2_star;
+                continue;
+            }
+            return Err(UdsError::NegativeResponse {
+                sid: request_sid,
+                code,
+            });
+        }
+
+        if response[0] == UDS_REQ_TESTERPRESENT + 0x40 && request_sid != UDS_REQ_TESTERPRESENT {
+            // Late positive response to a TesterPresent, e.g. from an ECU
+            // that ignores the suppress bit
+            continue;
+        }
+
+        if response[0] != request_sid + 0x40 {
+            return Err(UdsError::InvalidResponseId);
+        }
+
+        return Ok(response[1..].to_vec());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use crate::datalink::security::MazdaAlgorithm;
+
+    use super::*;
+
+    /// Replies to requests with canned responses and records the requests.
+    struct MockUds {
+        responses: RefCell<Vec<Result<Vec<u8>, UdsError>>>,
+        requests: RefCell<Vec<(u8, Vec<u8>)>>,
+    }
+
+    impl MockUds {
+        fn new(responses: Vec<Result<Vec<u8>, UdsError>>) -> MockUds {
+            MockUds {
+                responses: RefCell::new(responses),
+                requests: RefCell::new(Vec::new()),
+            }
+        }
+    }
+
+    impl UdsInterface for MockUds {
+        fn request(&self, request_sid: u8, data: &[u8]) -> Result<Vec<u8>, UdsError> {
+            self.requests
+                .borrow_mut()
+                .push((request_sid, data.to_vec()));
+            self.responses.borrow_mut().remove(0)
+        }
+    }
+
+    #[test]
+    fn request_over_isotp() {
+        use std::thread;
+        use std::time::Duration;
+
+        use crate::datalink::can::VirtualBus;
+        use crate::datalink::isotp::IsotpCan;
+
+        let bus = VirtualBus::new();
+        let ecu = IsotpCan::new(bus.endpoint(), 0x7E8, 0x7E0, Duration::from_secs(1));
+        let tester = IsotpCan::new(bus.endpoint(), 0x7E0, 0x7E8, Duration::from_secs(1));
+
+        let server = thread::spawn(move || {
+            assert_eq!(ecu.read_isotp().unwrap(), vec![UDS_REQ_SESSION, 0x87]);
+            ecu.write_isotp(&[0x7F, UDS_REQ_SESSION, 0x78]).unwrap();
+            ecu.write_isotp(&[UDS_REQ_SESSION + 0x40, 0x87, 0x00, 0x32])
+                .unwrap();
+
+            assert_eq!(ecu.read_isotp().unwrap(), vec![UDS_REQ_SECURITY, 0x01]);
+            ecu.write_isotp(&[0x7F, UDS_REQ_SECURITY, 0x22]).unwrap();
+        });
+
+        let uds: &dyn Isotp = &tester;
+        assert_eq!(uds.request_session(0x87).unwrap(), vec![0x00, 0x32]);
+        assert!(matches!(
+            uds.request_security_seed(1),
+            Err(UdsError::NegativeResponse {
+                sid: UDS_REQ_SECURITY,
+                code: NegativeResponseCode::ConditionsNotCorrect,
+            })
+        ));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn unlock() {
+        let uds = MockUds::new(vec![Ok(vec![0x01, 0x12, 0x34, 0x56]), Ok(vec![0x02])]);
+        uds.unlock(1, &MazdaAlgorithm::default()).unwrap();
+        assert_eq!(
+            *uds.requests.borrow(),
+            vec![
+                (UDS_REQ_SECURITY, vec![0x01]),
+                (UDS_REQ_SECURITY, vec![0x02, 0x86, 0xCA, 0x06]),
+            ]
+        );
+    }
+
+    #[test]
+    fn unlock_already_unlocked() {
+        let uds = MockUds::new(vec![Ok(vec![0x03, 0x00, 0x00, 0x00])]);
+        uds.unlock(3, &MazdaAlgorithm::default()).unwrap();
+        assert_eq!(uds.requests.borrow().len(), 1);
+    }
+
+    #[test]
+    fn unlock_invalid_seed() {
+        let uds = MockUds::new(vec![Ok(vec![0x01, 0x12, 0x34])]);
+        assert!(matches!(
+            uds.unlock(1, &MazdaAlgorithm::default()),
+            Err(UdsError::InvalidSeed)
+        ));
+    }
+
+    #[test]
+    fn unlock_rejected_key() {
+        let uds = MockUds::new(vec![
+            Ok(vec![0x01, 0x12, 0x34, 0x56]),
+            Err(UdsError::NegativeResponse {
+                sid: UDS_REQ_SECURITY,
+                code: NegativeResponseCode::InvalidKey,
+            }),
+        ]);
+        let err = uds.unlock(1, &MazdaAlgorithm::default()).unwrap_err();
+        assert_eq!(
+            err.negative_response_code(),
+            Some(NegativeResponseCode::InvalidKey)
+        );
+        assert!(!err.is_retryable());
+    }
+
+    #[test]
+    fn negative_response_code() {
+        for code in 0..=0xFF_u8 {
+            assert_eq!(u8::from(NegativeResponseCode::from(code)), code);
+        }
+        assert_eq!(
+            NegativeResponseCode::from(0x33),
+            NegativeResponseCode::SecurityAccessDenied
+        );
+        assert_eq!(
+            NegativeResponseCode::from(0x50),
+            NegativeResponseCode::Other(0x50)
+        );
+        assert_eq!(
+            NegativeResponseCode::RequiredTimeDelayNotExpired.to_string(),
+            "requiredTimeDelayNotExpired (0x37)"
+        );
+        assert_eq!(
+            NegativeResponseCode::Other(0xF3).to_string(),
+            "vehicleManufacturerSpecific (0xF3)"
+        );
+        assert!(NegativeResponseCode::BusyRepeatRequest.is_retryable());
+        assert!(NegativeResponseCode::BusyRepeatRequest.requires_delay());
+        assert!(!NegativeResponseCode::ConditionsNotCorrect.requires_delay());
+        assert!(!NegativeResponseCode::RequestOutOfRange.is_retryable());
+        assert!(!NegativeResponseCode::ResponsePending.is_retryable());
+        assert!(UdsError::TimedOut.is_retryable());
+        assert!(!UdsError::InvalidAddressFormat.is_retryable());
+        assert!(!UdsError::Unsupported.is_retryable());
+    }
+
+    #[test]
+    fn request_timing() {
+        use std::thread;
+
+        use crate::datalink::can::VirtualBus;
+        use crate::datalink::isotp::IsotpCan;
+
+        let bus = VirtualBus::new();
+        let ecu = IsotpCan::new(bus.endpoint(), 0x7E8, 0x7E0, Duration::from_secs(1));
+        let tester = IsotpUds::new(
+            IsotpCan::new(bus.endpoint(), 0x7E0, 0x7E8, Duration::from_secs(1)),
+            UdsTiming {
+                p2: Duration::from_millis(50),
+                p2_star: Duration::from_secs(1),
+                deadline: Some(Duration::from_secs(2)),
+            },
+        );
+
+        let server = thread::spawn(move || {
+            // Long operation: pending responses arrive within P2*
+            ecu.read_isotp().unwrap();
+            for _ in 0..3 {
+                ecu.write_isotp(&[0x7F, UDS_REQ_ROUTINECONTROL, 0x78])
+                    .unwrap();
+                thread::sleep(Duration::from_millis(100));
+            }
+            ecu.write_isotp(&[UDS_REQ_ROUTINECONTROL + 0x40, 0x01, 0xFF, 0x00])
+                .unwrap();
+
+            // No response at all
+            ecu.read_isotp().unwrap();
+
+            // Pending responses that outlast the deadline
+            ecu.read_isotp().unwrap();
+            for _ in 0..25 {
+                ecu.write_isotp(&[0x7F, UDS_REQ_ROUTINECONTROL, 0x78])
+                    .unwrap();
+                thread::sleep(Duration::from_millis(100));
+            }
+        });
+
+        assert_eq!(
+            tester
+                .request(UDS_REQ_ROUTINECONTROL, &[0x01, 0xFF, 0x00])
+                .unwrap(),
+            vec![0x01, 0xFF, 0x00]
+        );
+
+        let start = Instant::now();
+        assert!(matches!(
+            tester.request(UDS_REQ_SESSION, &[0x85]),
+            Err(UdsError::TimedOut)
+        ));
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(50) && elapsed < Duration::from_millis(500));
+
+        let start = Instant::now();
+        assert!(matches!(
+            tester.request(UDS_REQ_ROUTINECONTROL, &[0x01, 0xFF, 0x00]),
+            Err(UdsError::TimedOut)
+        ));
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(2) && elapsed < Duration::from_secs(3));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn write_data_by_identifier() {
+        let uds = MockUds::new(vec![Ok(vec![0xF1, 0x90]), Ok(vec![0xF1, 0x91])]);
+        uds.write_data_by_identifier(0xF190, b"VIN").unwrap();
+        assert_eq!(
+            uds.requests.borrow()[0],
+            (UDS_REQ_WRITEDATABYID, vec![0xF1, 0x90, b'V', b'I', b'N'])
+        );
+        assert!(matches!(
+            uds.write_data_by_identifier(0xF190, b"VIN"),
+            Err(UdsError::InvalidResponse)
+        ));
+    }
+
+    #[test]
+    fn write_memory_by_address() {
+        let format = MemoryAddressFormat::new(4, 2).unwrap();
+        let echo = vec![0x24, 0xFF, 0xFF, 0x80, 0x00, 0x00, 0x02];
+        let uds = MockUds::new(vec![Ok(echo.clone()), Ok(echo[..6].to_vec())]);
+        uds.write_memory_by_address(format, 0xFFFF_8000, &[0xAB, 0xCD])
+            .unwrap();
+        let mut request = echo.clone();
+        request.extend_from_slice(&[0xAB, 0xCD]);
+        assert_eq!(uds.requests.borrow()[0], (UDS_REQ_WRITEMEM, request));
+        assert!(matches!(
+            uds.write_memory_by_address(format, 0xFFFF_8000, &[0xAB, 0xCD]),
+            Err(UdsError::InvalidResponse)
+        ));
+    }
+
+    #[test]
+    fn memory_address_format() {
+        let mut request = Vec::new();
+        MemoryAddressFormat::new(3, 1)
+            .unwrap()
+            .encode(0x12_3456, 0x80, &mut request)
+            .unwrap();
+        assert_eq!(request, vec![0x13, 0x12, 0x34, 0x56, 0x80]);
+
+        request.clear();
+        MemoryAddressFormat::default()
+            .encode(0xFFFF_8000, 0x800, &mut request)
+            .unwrap();
+        assert_eq!(
+            request,
+            vec![0x44, 0xFF, 0xFF, 0x80, 0x00, 0x00, 0x00, 0x08, 0x00]
+        );
+
+        request.clear();
+        let format = MemoryAddressFormat::without_alfid(4, 2).unwrap();
+        assert!(!format.has_alfid());
+        format.encode(0x8000, 0x800, &mut request).unwrap();
+        assert_eq!(request, vec![0x00, 0x00, 0x80, 0x00, 0x08, 0x00]);
+
+        let format = MemoryAddressFormat::new(2, 1).unwrap();
+        assert!(matches!(
+            format.encode(0x1_0000, 1, &mut request),
+            Err(UdsError::InvalidAddressFormat)
+        ));
+        assert!(matches!(
+            format.encode(0, 0x100, &mut request),
+            Err(UdsError::InvalidAddressFormat)
+        ));
+        assert!(matches!(
+            MemoryAddressFormat::new(0, 4),
+            Err(UdsError::InvalidAddressFormat)
+        ));
+        assert!(matches!(
+            MemoryAddressFormat::without_alfid(4, 5),
+            Err(UdsError::InvalidAddressFormat)
+        ));
+    }
+
+    #[test]
+    fn request_download() {
+        let uds = MockUds::new(vec![Ok(vec![0x20, 0x04, 0x02]), Ok(vec![0x30, 0x04, 0x02])]);
+        let format = MemoryAddressFormat::new(3, 2).unwrap();
+        assert_eq!(
+            uds.request_download(0x00, format, 0x1000, 0x400).unwrap(),
+            0x402
+        );
+        assert_eq!(
+            uds.requests.borrow()[0],
+            (
+                UDS_REQ_REQUESTDOWNLOAD,
+                vec![0x00, 0x23, 0x00, 0x10, 0x00, 0x04, 0x00]
+            )
+        );
+        assert!(matches!(
+            uds.request_upload(0x00, format, 0x1000, 0x400),
+            Err(UdsError::InvalidResponse)
+        ));
+    }
+
+    #[test]
+    fn routine_control() {
+        let uds = MockUds::new(vec![
+            Ok(vec![0x01, 0xFF, 0x01, 0x00]),
+            Ok(vec![0x03, 0x02, 0x02, 0x00, 0x12]),
+            Ok(vec![0x02, 0xFF, 0x00]),
+        ]);
+        assert_eq!(
+            uds.start_routine(UDS_ROUTINE_CHECK_PROGRAMMING_DEPENDENCIES, &[])
+                .unwrap(),
+            vec![0x00]
+        );
+        assert_eq!(
+            uds.request_routine_results(0x0202).unwrap(),
+            vec![0x00, 0x12]
+        );
+        // Wrong routineIdentifier
+        assert!(matches!(
+            uds.stop_routine(0xFF01, &[0xAA]),
+            Err(UdsError::InvalidResponse)
+        ));
+        assert_eq!(
+            *uds.requests.borrow(),
+            vec![
+                (UDS_REQ_ROUTINECONTROL, vec![0x01, 0xFF, 0x01]),
+                (UDS_REQ_ROUTINECONTROL, vec![0x03, 0x02, 0x02]),
+                (UDS_REQ_ROUTINECONTROL, vec![0x02, 0xFF, 0x01, 0xAA]),
+            ]
+        );
+    }
+
+    #[test]
+    fn stale_tester_present() {
+        /// Replays queued packets to every read.
+        struct Replay(RefCell<Vec<Vec<u8>>>);
+
+        impl Isotp for Replay {
+            fn read_isotp(&self) -> Result<Vec<u8>, IsotpError> {
+                let mut packets = self.0.borrow_mut();
+                if packets.is_empty() {
+                    return Err(IsotpError::TimedOut);
                 }
-                return Err(UdsError::NegativeResponse(0));
+                Ok(packets.remove(0))
             }
 
-            if response[0] != request_sid + 0x40 {
-                return Err(UdsError::InvalidResponseId);
+            fn write_isotp(&self, _data: &[u8]) -> Result<(), IsotpError> {
+                Ok(())
             }
+        }
 
-            return Ok(response[1..].to_vec());
+        let uds = IsotpUds::new(
+            Replay(RefCell::new(vec![
+                vec![0x7E, 0x00],
+                vec![0x7F, UDS_REQ_TESTERPRESENT, 0x12],
+                vec![0x50, 0x85],
+            ])),
+            UdsTiming::default(),
+        );
+        assert_eq!(uds.request(UDS_REQ_SESSION, &[0x85]).unwrap(), vec![0x85]);
+    }
+
+    #[test]
+    fn functional() {
+        use crate::datalink::can::VirtualBus;
+        use crate::datalink::isotp::IsotpCan;
+
+        /// Records the packets sent to the physical and functional addresses.
+        #[derive(Default)]
+        struct Recorder {
+            physical: RefCell<Vec<Vec<u8>>>,
+            functional: RefCell<Vec<Vec<u8>>>,
         }
+
+        impl Isotp for Recorder {
+            fn read_isotp(&self) -> Result<Vec<u8>, IsotpError> {
+                Err(IsotpError::TimedOut)
+            }
+
+            fn write_isotp(&self, data: &[u8]) -> Result<(), IsotpError> {
+                self.physical.borrow_mut().push(data.to_vec());
+                Ok(())
+            }
+
+            fn write_isotp_functional(&self, data: &[u8]) -> Result<(), IsotpError> {
+                self.functional.borrow_mut().push(data.to_vec());
+                Ok(())
+            }
+        }
+
+        let uds = IsotpUds::new(Recorder::default(), UdsTiming::default());
+        uds.communication_control_functional(
+            CommunicationControlType::DisableRxAndTx,
+            UDS_COMMUNICATION_NORMAL,
+        )
+        .unwrap();
+        uds.control_dtc_setting_functional(DtcSettingType::Off)
+            .unwrap();
+        uds.ecu_reset_functional(ResetType::HardReset).unwrap();
+        assert!(uds.isotp.physical.borrow().is_empty());
+        assert_eq!(
+            *uds.isotp.functional.borrow(),
+            vec![vec![0x28, 0x83, 0x01], vec![0x85, 0x82], vec![0x11, 0x81]]
+        );
+
+        // Interfaces without functional addressing
+        let uds = MockUds::new(Vec::new());
+        assert!(matches!(
+            uds.ecu_reset_functional(ResetType::HardReset),
+            Err(UdsError::Unsupported)
+        ));
+        let bus = VirtualBus::new();
+        let uds = IsotpUds::new(
+            IsotpCan::new(bus.endpoint(), 0x7E0, 0x7E8, Duration::from_millis(10)),
+            UdsTiming::default(),
+        );
+        assert!(matches!(
+            uds.ecu_reset_functional(ResetType::HardReset),
+            Err(UdsError::Unsupported)
+        ));
+    }
+
+    #[test]
+    fn ecu_reset() {
+        let uds = MockUds::new(vec![
+            Ok(vec![0x01]),
+            Ok(vec![0x04, 0x0A]),
+            Ok(vec![0x02]),
+            Ok(vec![0x02]),
+        ]);
+        assert_eq!(uds.ecu_reset(ResetType::HardReset).unwrap(), None);
+        assert_eq!(
+            uds.ecu_reset(ResetType::EnableRapidPowerShutDown).unwrap(),
+            Some(0x0A)
+        );
+        assert!(matches!(
+            uds.ecu_reset(ResetType::SoftReset),
+            Err(UdsError::InvalidResponse)
+        ));
+        assert!(matches!(
+            uds.control_dtc_setting(DtcSettingType::On),
+            Err(UdsError::InvalidResponse)
+        ));
     }
 }
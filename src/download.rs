@@ -0,0 +1,286 @@
+use std::cmp;
+use std::thread;
+use std::time::Duration;
+
+use thiserror::Error;
+
+use crate::datalink::uds::{MemoryAddressFormat, NegativeResponseCode, UdsError, UdsInterface};
+use crate::platform::Platform;
+use crate::Rom;
+
+#[derive(Error, Debug)]
+pub enum DownloadError {
+    #[error(transparent)]
+    Uds(#[from] UdsError),
+
+    #[error("download cancelled")]
+    Cancelled,
+
+    /// Occurs when [`DownloadOptions::block_size`] is 0.
+    #[error("download block size must not be 0")]
+    InvalidBlockSize,
+}
+
+/// Options for [`download_rom`].
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    /// Number of bytes requested per ReadMemoryByAddress request
+    pub block_size: u16,
+
+    /// Number of times a failed block is requested again before giving up
+    pub retries: usize,
+
+    /// Delay before the first retry of a block the ECU asked to delay, e.g.
+    /// with busyRepeatRequest. The delay doubles with every further retry.
+    pub retry_delay: Duration,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> DownloadOptions {
+        DownloadOptions {
+            block_size: 0x800,
+            retries: 3,
+            retry_delay: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Downloads the ROM of `platform` from the ECU using ReadMemoryByAddress.
+///
+/// Opens the platform's download session and unlocks its security level
+/// before reading. `progress` is called with (bytes read, total bytes) after
+/// each block; returning `false` cancels the download with
+/// [`DownloadError::Cancelled`].
+pub fn download_rom<P, U, F>(
+    uds: &U,
+    platform: &P,
+    options: &DownloadOptions,
+    mut progress: F,
+) -> Result<Rom, DownloadError>
+    where
+        P: Platform,
+        U: UdsInterface + ?Sized,
+        F: FnMut(usize, usize) -> bool,
+{
+    if options.block_size == 0 {
+        return Err(DownloadError::InvalidBlockSize);
+    }
+
+    uds.request_session(platform.download_session())?;
+    uds.unlock(
+        platform.security_level(),
+        platform.security_algorithm().as_ref(),
+    )?;
+
+    let total = platform.rom_length();
+    let mut data = Vec::with_capacity(total);
+    while data.len() < total {
+        if !progress(data.len(), total) {
+            return Err(DownloadError::Cancelled);
+        }
+
+        let address = platform.rom_address() + data.len() as u32;
+        let length = cmp::min(total - data.len(), options.block_size as usize) as u32;
+        data.extend_from_slice(&read_block(
+            uds,
+            platform.memory_address_format(),
+            address,
+            length,
+            options,
+        )?);
+    }
+    progress(total, total);
+
+    Ok(Rom::from(data))
+}
+
+/// Reads a block of memory, requesting it again up to `options.retries` times
+/// if the request fails with a retryable error. Retries after a negative
+/// response that [requires a delay](NegativeResponseCode::requires_delay) back
+/// off exponentially from `options.retry_delay`.
+pub(crate) fn read_block<U: UdsInterface + ?Sized>(
+    uds: &U,
+    format: MemoryAddressFormat,
+    address: u32,
+    length: u32,
+    options: &DownloadOptions,
+) -> Result<Vec<u8>, UdsError> {
+    let mut attempt = 0;
+    loop {
+        let res = uds
+            .request_read_memory_address(format, address, length)
+            .and_then(|block| {
+                if block.len() != length as usize {
+                    return Err(UdsError::InvalidResponse);
+                }
+                Ok(block)
+            });
+        match res {
+            Err(err) if attempt < options.retries && err.is_retryable() => {
+                if err
+                    .negative_response_code()
+                    .is_some_and(NegativeResponseCode::requires_delay)
+                {
+                    thread::sleep(options.retry_delay * 2_u32.pow(cmp::min(attempt, 16) as u32));
+                }
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::convert::TryInto;
+
+    use crate::datalink::uds::{
+        NegativeResponseCode, UDS_REQ_READMEM, UDS_REQ_SECURITY, UDS_REQ_SESSION,
+    };
+    use crate::platform::Mazdaspeed6;
+
+    use super::*;
+
+    /// Serves ReadMemoryByAddress requests from a memory image. Every
+    /// `fail_every`th read request fails with `fail_code`.
+    struct MockEcu {
+        memory: Vec<u8>,
+        fail_every: usize,
+        fail_code: NegativeResponseCode,
+        reads: Cell<usize>,
+    }
+
+    impl UdsInterface for MockEcu {
+        fn request(&self, request_sid: u8, data: &[u8]) -> Result<Vec<u8>, UdsError> {
+            match request_sid {
+                UDS_REQ_SESSION => Ok(data.to_vec()),
+                UDS_REQ_SECURITY => Ok(vec![data[0], 0, 0, 0]),
+                UDS_REQ_READMEM => {
+                    self.reads.set(self.reads.get() + 1);
+                    if self.fail_every > 0 && self.reads.get() % self.fail_every == 0 {
+                        return Err(UdsError::NegativeResponse {
+                            sid: request_sid,
+                            code: self.fail_code,
+                        });
+                    }
+                    // 4-byte address and 2-byte size without ALFID
+                    assert_eq!(data.len(), 6);
+                    let address = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+                    let length = u16::from_be_bytes(data[4..6].try_into().unwrap()) as usize;
+                    Ok(self.memory[address..address + length].to_vec())
+                }
+                _ => Err(UdsError::NegativeResponse {
+                    sid: request_sid,
+                    code: NegativeResponseCode::ServiceNotSupported,
+                }),
+            }
+        }
+    }
+
+    fn mock_ecu(fail_every: usize) -> MockEcu {
+        MockEcu {
+            memory: (0..Mazdaspeed6.rom_length())
+                .map(|i| (i % 251) as u8)
+                .collect(),
+            fail_every,
+            fail_code: NegativeResponseCode::ConditionsNotCorrect,
+            reads: Cell::new(0),
+        }
+    }
+
+    #[test]
+    fn download() {
+        let ecu = mock_ecu(0);
+        let mut last = (0, 0);
+        let rom = download_rom(
+            &ecu,
+            &Mazdaspeed6,
+            &DownloadOptions::default(),
+            |n, total| {
+                assert!(n >= last.0);
+                last = (n, total);
+                true
+            },
+        )
+        .unwrap();
+        assert_eq!(rom.data(), &ecu.memory[..]);
+        assert_eq!(last, (ecu.memory.len(), ecu.memory.len()));
+        assert_eq!(ecu.reads.get(), ecu.memory.len() / 0x800);
+    }
+
+    #[test]
+    fn download_retry() {
+        let ecu = mock_ecu(3);
+        let rom =
+            download_rom(&ecu, &Mazdaspeed6, &DownloadOptions::default(), |_, _| true).unwrap();
+        assert_eq!(rom.data(), &ecu.memory[..]);
+
+        let ecu = mock_ecu(1);
+        assert!(matches!(
+            download_rom(&ecu, &Mazdaspeed6, &DownloadOptions::default(), |_, _| true),
+            Err(DownloadError::Uds(UdsError::NegativeResponse {
+                code: NegativeResponseCode::ConditionsNotCorrect,
+                ..
+            }))
+        ));
+        assert_eq!(ecu.reads.get(), 4);
+
+        // Requests that can not succeed are not retried
+        let ecu = MockEcu {
+            fail_code: NegativeResponseCode::RequestOutOfRange,
+            ..mock_ecu(1)
+        };
+        assert!(
+            download_rom(&ecu, &Mazdaspeed6, &DownloadOptions::default(), |_, _| true).is_err()
+        );
+        assert_eq!(ecu.reads.get(), 1);
+    }
+
+    #[test]
+    fn retry_delay() {
+        use std::time::Instant;
+
+        let ecu = MockEcu {
+            fail_code: NegativeResponseCode::BusyRepeatRequest,
+            ..mock_ecu(1)
+        };
+        let options = DownloadOptions {
+            retry_delay: Duration::from_millis(20),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        assert!(read_block(&ecu, Mazdaspeed6.memory_address_format(), 0, 0x10, &options).is_err());
+        // 20 + 40 + 80 ms
+        assert!(start.elapsed() >= Duration::from_millis(140));
+        assert_eq!(ecu.reads.get(), 4);
+    }
+
+    #[test]
+    fn download_cancel() {
+        let ecu = mock_ecu(0);
+        let options = DownloadOptions {
+            block_size: 0x1000,
+            ..Default::default()
+        };
+        assert!(matches!(
+            download_rom(&ecu, &Mazdaspeed6, &options, |n, _| n < 0x4000),
+            Err(DownloadError::Cancelled)
+        ));
+        assert_eq!(ecu.reads.get(), 4);
+    }
+
+    #[test]
+    fn download_invalid_block_size() {
+        let ecu = mock_ecu(0);
+        let options = DownloadOptions {
+            block_size: 0,
+            ..Default::default()
+        };
+        assert!(matches!(
+            download_rom(&ecu, &Mazdaspeed6, &options, |_, _| true),
+            Err(DownloadError::InvalidBlockSize)
+        ));
+        assert_eq!(ecu.reads.get(), 0);
+    }
+}
@@ -0,0 +1,762 @@
+use std::cmp;
+use std::ops::Range;
+
+use thiserror::Error;
+
+use crate::datalink::uds::{UdsError, UdsInterface};
+use crate::download::{download_rom, DownloadError, DownloadOptions};
+use crate::platform::Download;
+use crate::{Rom, RomError};
+
+#[derive(Error, Debug)]
+pub enum FlashError {
+    #[error(transparent)]
+    Uds(#[from] UdsError),
+
+    #[error(transparent)]
+    Rom(#[from] RomError),
+
+    /// Occurs when the ROM to be flashed has an invalid checksum.
+    #[error("ROM checksum `{0}` is invalid")]
+    InvalidChecksum(String),
+
+    #[error("ECU memory does not match the ROM after flashing")]
+    VerificationFailed,
+
+    /// Occurs when a check routine of the platform returns an unexpected status.
+    #[error("routine 0x{id:04X} failed with status {status:02X?}")]
+    RoutineFailed { id: u16, status: Vec<u8> },
+
+    /// Occurs when a configured block size is 0.
+    #[error("block size must not be 0")]
+    InvalidBlockSize,
+
+    /// Occurs when the maxNumberOfBlockLength returned by RequestDownload
+    /// leaves no room for data after the TransferData SID and
+    /// blockSequenceCounter.
+    #[error("maxNumberOfBlockLength {0} is too small for TransferData")]
+    InvalidMaxBlockLength(usize),
+
+    #[error("flash cancelled")]
+    Cancelled,
+}
+
+impl From<DownloadError> for FlashError {
+    fn from(err: DownloadError) -> FlashError {
+        match err {
+            DownloadError::Uds(err) => FlashError::Uds(err),
+            DownloadError::Cancelled => FlashError::Cancelled,
+            DownloadError::InvalidBlockSize => FlashError::InvalidBlockSize,
+        }
+    }
+}
+
+/// Progress events emitted by [`flash_rom`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FlashEvent {
+    Reading { read: usize, total: usize },
+    Erasing,
+    Transferring { transferred: usize, total: usize },
+    Verifying,
+}
+
+/// Options for [`flash_rom`].
+#[derive(Debug, Clone)]
+pub struct FlashOptions {
+    /// dataFormatIdentifier sent with RequestDownload. 0 means the data is
+    /// neither compressed nor encrypted.
+    pub data_format: u8,
+
+    /// Upper bound on the number of data bytes sent per TransferData
+    /// request. The ECU's maxNumberOfBlockLength may lower it further.
+    pub max_block_size: usize,
+}
+
+impl Default for FlashOptions {
+    fn default() -> FlashOptions {
+        FlashOptions {
+            data_format: 0,
+            max_block_size: 0x800,
+        }
+    }
+}
+
+/// Flashes `rom` to the ECU of `platform`.
+///
+/// The ROM must have the platform's length and valid checksums. The
+/// platform's programming session is opened and its security level unlocked,
+/// then the ROM is erased, transferred with TransferData and read back for
+/// verification.
+///
+/// `progress` is called with each [`FlashEvent`]; returning `false` cancels
+/// the flash with [`FlashError::Cancelled`]. Cancelling after
+/// [`FlashEvent::Erasing`] leaves the ECU unprogrammed.
+pub fn flash_rom<D, U, F>(
+    uds: &U,
+    platform: &D,
+    rom: &Rom,
+    options: &FlashOptions,
+    mut progress: F,
+) -> Result<(), FlashError>
+    where
+        D: Download,
+        U: UdsInterface + ?Sized,
+        F: FnMut(FlashEvent) -> bool,
+{
+    validate(platform, rom, options)?;
+
+    let all = 0..rom.len();
+    program(
+        uds,
+        platform,
+        rom,
+        std::slice::from_ref(&all),
+        options,
+        &mut progress,
+    )?;
+
+    if !progress(FlashEvent::Verifying) {
+        return Err(FlashError::Cancelled);
+    }
+    if !platform.verify(uds, rom, all)? {
+        return Err(FlashError::VerificationFailed);
+    }
+    Ok(())
+}
+
+/// Flashes only the sectors of `rom` that differ from the ECU memory.
+///
+/// `base` is the image currently on the ECU. If it is `None`, the image is
+/// read from the ECU first with [`download_rom`], emitting
+/// [`FlashEvent::Reading`]. Changed sectors are erased, written and verified.
+/// Unchanged sectors are not read back: `base` must match the ECU memory, as
+/// a sector that differs on the ECU but not in `base` is left unflashed.
+/// Finally, the checksums of `base` with the flashed sectors replaced are
+/// compared against the checksums of `rom`.
+///
+/// Returns the ROM offsets of the sectors that were flashed. Progress and
+/// cancellation work as in [`flash_rom`].
+pub fn flash_rom_delta<D, U, F>(
+    uds: &U,
+    platform: &D,
+    rom: &Rom,
+    base: Option<&Rom>,
+    options: &FlashOptions,
+    mut progress: F,
+) -> Result<Vec<Range<usize>>, FlashError>
+    where
+        D: Download,
+        U: UdsInterface + ?Sized,
+        F: FnMut(FlashEvent) -> bool,
+{
+    validate(platform, rom, options)?;
+
+    let downloaded;
+    let base = match base {
+        Some(base) => base,
+        None => {
+            downloaded =
+                download_rom(uds, platform, &DownloadOptions::default(), |read, total| {
+                    progress(FlashEvent::Reading { read, total })
+                })?;
+            &downloaded
+        }
+    };
+    if base.len() != rom.len() {
+        return Err(RomError::InvalidSize {
+            expected: rom.len(),
+            found: base.len(),
+        }
+        .into());
+    }
+
+    let sectors: Vec<Range<usize>> = platform
+        .sectors()
+        .into_iter()
+        .filter(|sector| rom.data()[sector.clone()] != base.data()[sector.clone()])
+        .collect();
+    if sectors.is_empty() {
+        return Ok(sectors);
+    }
+
+    program(uds, platform, rom, &sectors, options, &mut progress)?;
+
+    if !progress(FlashEvent::Verifying) {
+        return Err(FlashError::Cancelled);
+    }
+    for sector in &sectors {
+        if !platform.verify(uds, rom, sector.clone())? {
+            return Err(FlashError::VerificationFailed);
+        }
+    }
+
+    // The flashed sectors were verified against `rom` and the rest of the ECU
+    // still holds `base`. Compare the checksums of the resulting image.
+    let mut image = base.data().to_vec();
+    for sector in &sectors {
+        image[sector.clone()].copy_from_slice(&rom.data()[sector.clone()]);
+    }
+    let expected = rom.verify_checksums(platform)?;
+    let found = Rom::from(image).verify_checksums(platform)?;
+    if expected
+        .iter()
+        .zip(&found)
+        .any(|(e, f)| !f.is_valid() || e.computed != f.computed)
+    {
+        return Err(FlashError::VerificationFailed);
+    }
+    Ok(sectors)
+}
+
+/// Checks that `options` can transfer data and that `rom` has the length of
+/// `platform` and valid checksums.
+fn validate<D: Download>(
+    platform: &D,
+    rom: &Rom,
+    options: &FlashOptions,
+) -> Result<(), FlashError> {
+    if options.max_block_size == 0 {
+        return Err(FlashError::InvalidBlockSize);
+    }
+    let expected = platform.rom_length();
+    if rom.len() != expected {
+        return Err(RomError::InvalidSize {
+            expected,
+            found: rom.len(),
+        }
+        .into());
+    }
+    if let Some(result) = rom
+        .verify_checksums(platform)?
+        .into_iter()
+        .find(|result| !result.is_valid())
+    {
+        return Err(FlashError::InvalidChecksum(result.region.name));
+    }
+    Ok(())
+}
+
+/// Opens the programming session, unlocks the ECU, erases and writes each of
+/// `regions` (ROM offsets), then runs the platform's check routines.
+fn program<D, U, F>(
+    uds: &U,
+    platform: &D,
+    rom: &Rom,
+    regions: &[Range<usize>],
+    options: &FlashOptions,
+    progress: &mut F,
+) -> Result<(), FlashError>
+    where
+        D: Download,
+        U: UdsInterface + ?Sized,
+        F: FnMut(FlashEvent) -> bool,
+{
+    uds.request_session(platform.programming_session())?;
+    uds.unlock(
+        platform.security_level(),
+        platform.security_algorithm().as_ref(),
+    )?;
+
+    let total = regions.iter().map(|r| r.len()).sum();
+    let mut transferred = 0;
+    for region in regions {
+        let address = platform.rom_address() + region.start as u32;
+        let length = region.len() as u32;
+        if !progress(FlashEvent::Erasing) {
+            return Err(FlashError::Cancelled);
+        }
+        platform.erase(uds, address, length)?;
+
+        let max_block_length = uds.request_download(
+            options.data_format,
+            platform.download_address_format(),
+            address,
+            length,
+        )?;
+        // maxNumberOfBlockLength includes the SID and blockSequenceCounter
+        if max_block_length <= 2 {
+            return Err(FlashError::InvalidMaxBlockLength(max_block_length));
+        }
+        let block_size = cmp::min(max_block_length - 2, options.max_block_size);
+
+        let mut sequence: u8 = 1;
+        for block in rom.data()[region.clone()].chunks(block_size) {
+            if !progress(FlashEvent::Transferring { transferred, total }) {
+                return Err(FlashError::Cancelled);
+            }
+            uds.transfer_data(sequence, block)?;
+            sequence = sequence.wrapping_add(1);
+            transferred += block.len();
+        }
+        uds.request_transfer_exit()?;
+    }
+    progress(FlashEvent::Transferring { transferred, total });
+
+    for routine in platform.check_routines() {
+        let status = uds.start_routine(routine.id, &routine.option)?;
+        if status != routine.expected_status {
+            return Err(FlashError::RoutineFailed {
+                id: routine.id,
+                status,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::convert::TryInto;
+
+    use byteordered::Endianness;
+
+    use crate::checksum::{ChecksumAlgorithm, ChecksumRegion};
+    use crate::datalink::security::SecurityAlgorithm;
+    use crate::datalink::uds::UDS_ROUTINE_CHECK_PROGRAMMING_DEPENDENCIES;
+    use crate::datalink::uds::{
+        MemoryAddressFormat, NegativeResponseCode, UDS_REQ_READMEM, UDS_REQ_REQUESTDOWNLOAD,
+        UDS_REQ_ROUTINECONTROL, UDS_REQ_SECURITY, UDS_REQ_SESSION, UDS_REQ_TRANSFERDATA,
+        UDS_REQ_TRANSFEREXIT,
+    };
+    use crate::platform::{Mazdaspeed6, Platform, Routine};
+    use crate::table::{Axis, Table};
+
+    use super::*;
+
+    /// Flash memory that is programmed with RequestDownload and TransferData.
+    struct MockEcu {
+        memory: RefCell<Vec<u8>>,
+        /// Next write address and expected blockSequenceCounter
+        download: Cell<Option<(usize, u8)>>,
+        /// Erased ranges in the order they were erased
+        erased: RefCell<Vec<Range<usize>>>,
+        /// Bit flipped in every TransferData block to simulate a bad write
+        corrupt: bool,
+        /// routineStatusRecord of checkProgrammingDependencies
+        dependency_status: Vec<u8>,
+        /// maxNumberOfBlockLength returned by RequestDownload
+        max_block_length: u16,
+        /// Number of bytes read with ReadMemoryByAddress
+        read: Cell<usize>,
+    }
+
+    impl MockEcu {
+        fn new(corrupt: bool) -> MockEcu {
+            MockEcu {
+                memory: RefCell::new(vec![0x55; Mazdaspeed6.rom_length()]),
+                download: Cell::new(None),
+                erased: RefCell::new(Vec::new()),
+                corrupt,
+                dependency_status: vec![0x00],
+                max_block_length: 0x402,
+                read: Cell::new(0),
+            }
+        }
+    }
+
+    impl UdsInterface for MockEcu {
+        fn request(&self, request_sid: u8, data: &[u8]) -> Result<Vec<u8>, UdsError> {
+            let address = |d: &[u8]| u32::from_be_bytes(d[..4].try_into().unwrap()) as usize;
+            match request_sid {
+                UDS_REQ_SESSION => Ok(data.to_vec()),
+                UDS_REQ_SECURITY => Ok(vec![data[0], 0, 0, 0]),
+                UDS_REQ_ROUTINECONTROL if data[..3] == [0x01, 0xFF, 0x01] => {
+                    let mut response = data[..3].to_vec();
+                    response.extend_from_slice(&self.dependency_status);
+                    Ok(response)
+                }
+                UDS_REQ_ROUTINECONTROL => {
+                    assert_eq!(&data[..4], &[0x01, 0xFF, 0x00, 0x44]);
+                    let start = address(&data[4..]);
+                    let length = address(&data[8..]);
+                    for b in &mut self.memory.borrow_mut()[start..start + length] {
+                        *b = 0xFF;
+                    }
+                    self.erased.borrow_mut().push(start..start + length);
+                    Ok(data[..3].to_vec())
+                }
+                UDS_REQ_REQUESTDOWNLOAD => {
+                    let start = address(&data[2..]);
+                    if !self.erased.borrow().iter().any(|r| r.start == start) {
+                        return Err(UdsError::NegativeResponse {
+                            sid: request_sid,
+                            code: NegativeResponseCode::ConditionsNotCorrect,
+                        });
+                    }
+                    self.download.set(Some((start, 1)));
+                    let mut response = vec![0x20];
+                    response.extend_from_slice(&self.max_block_length.to_be_bytes());
+                    Ok(response)
+                }
+                UDS_REQ_TRANSFERDATA => {
+                    let (offset, sequence) =
+                        self.download.get().ok_or(UdsError::InvalidResponse)?;
+                    if data[0] != sequence {
+                        return Err(UdsError::NegativeResponse {
+                            sid: request_sid,
+                            code: NegativeResponseCode::WrongBlockSequenceCounter,
+                        });
+                    }
+                    let block = &data[1..];
+                    let mut memory = self.memory.borrow_mut();
+                    memory[offset..offset + block.len()].copy_from_slice(block);
+                    if self.corrupt {
+                        memory[offset] ^= 1;
+                    }
+                    self.download
+                        .set(Some((offset + block.len(), sequence.wrapping_add(1))));
+                    Ok(vec![sequence])
+                }
+                UDS_REQ_TRANSFEREXIT => {
+                    self.download.set(None);
+                    Ok(vec![])
+                }
+                UDS_REQ_READMEM => {
+                    // 4-byte address and 2-byte size without ALFID
+                    assert_eq!(data.len(), 6);
+                    let start = address(data);
+                    let length = u16::from_be_bytes([data[4], data[5]]) as usize;
+                    self.read.set(self.read.get() + length);
+                    Ok(self.memory.borrow()[start..start + length].to_vec())
+                }
+                _ => Err(UdsError::NegativeResponse {
+                    sid: request_sid,
+                    code: NegativeResponseCode::ServiceNotSupported,
+                }),
+            }
+        }
+    }
+
+    /// Mazdaspeed6 with a checksum in the last sector that checks programming
+    /// dependencies after flashing.
+    struct CheckedPlatform;
+
+    impl Platform for CheckedPlatform {
+        fn name() -> &'static str {
+            "Checked"
+        }
+
+        fn id() -> &'static str {
+            "checked"
+        }
+
+        fn table(&self, id: &str) -> Option<Table> {
+            Mazdaspeed6.table(id)
+        }
+
+        fn axis(&self, id: &str) -> Option<Axis> {
+            Mazdaspeed6.axis(id)
+        }
+
+        fn rom_length(&self) -> usize {
+            Mazdaspeed6.rom_length()
+        }
+
+        fn rom_address(&self) -> u32 {
+            Mazdaspeed6.rom_address()
+        }
+
+        fn memory_address_format(&self) -> MemoryAddressFormat {
+            Mazdaspeed6.memory_address_format()
+        }
+
+        fn download_session(&self) -> u8 {
+            Mazdaspeed6.download_session()
+        }
+
+        fn security_level(&self) -> u8 {
+            Mazdaspeed6.security_level()
+        }
+
+        fn security_algorithm(&self) -> Box<dyn SecurityAlgorithm> {
+            Mazdaspeed6.security_algorithm()
+        }
+
+        fn checksums(&self) -> Vec<ChecksumRegion> {
+            let length = self.rom_length() as u64;
+            vec![ChecksumRegion {
+                name: "ROM".to_string(),
+                start: 0,
+                end: length,
+                checksum_offset: length - 4,
+                algorithm: ChecksumAlgorithm::Sum32Target(0x5AA5_A55A),
+                endianness: Endianness::Big,
+            }]
+        }
+    }
+
+    impl Download for CheckedPlatform {
+        fn programming_session(&self) -> u8 {
+            Mazdaspeed6.programming_session()
+        }
+
+        fn download_address_format(&self) -> MemoryAddressFormat {
+            Mazdaspeed6.download_address_format()
+        }
+
+        fn sectors(&self) -> Vec<Range<usize>> {
+            Mazdaspeed6.sectors()
+        }
+
+        fn check_routines(&self) -> Vec<Routine> {
+            vec![Routine {
+                id: UDS_ROUTINE_CHECK_PROGRAMMING_DEPENDENCIES,
+                option: Vec::new(),
+                expected_status: vec![0x00],
+            }]
+        }
+    }
+
+    fn test_rom() -> Rom {
+        let mut rom = Rom::from(
+            (0..Mazdaspeed6.rom_length())
+                .map(|i| (i % 253) as u8)
+                .collect::<Vec<u8>>(),
+        );
+        rom.fix_checksums(&CheckedPlatform).unwrap();
+        rom
+    }
+
+    #[test]
+    fn flash() {
+        let ecu = MockEcu::new(false);
+        let rom = test_rom();
+        let mut events = Vec::new();
+        flash_rom(
+            &ecu,
+            &Mazdaspeed6,
+            &rom,
+            &FlashOptions::default(),
+            |event| {
+                events.push(event);
+                true
+            },
+        )
+        .unwrap();
+        assert_eq!(&ecu.memory.borrow()[..], rom.data());
+
+        // maxNumberOfBlockLength 0x402 allows 0x400 bytes per block
+        let total = rom.len();
+        assert_eq!(events[0], FlashEvent::Erasing);
+        assert_eq!(
+            events[2],
+            FlashEvent::Transferring {
+                transferred: 0x400,
+                total
+            }
+        );
+        assert_eq!(events.len(), 1 + total / 0x400 + 1 + 1);
+        assert_eq!(events.last(), Some(&FlashEvent::Verifying));
+    }
+
+    #[test]
+    fn flash_check_routines() {
+        let rom = test_rom();
+        let ecu = MockEcu::new(false);
+        flash_rom(
+            &ecu,
+            &CheckedPlatform,
+            &rom,
+            &FlashOptions::default(),
+            |_| true,
+        )
+        .unwrap();
+
+        let ecu = MockEcu {
+            dependency_status: vec![0x01],
+            ..MockEcu::new(false)
+        };
+        assert!(matches!(
+            flash_rom(&ecu, &CheckedPlatform, &rom, &FlashOptions::default(), |_| true),
+            Err(FlashError::RoutineFailed { id: 0xFF01, status }) if status == [0x01]
+        ));
+    }
+
+    #[test]
+    fn flash_verification_failed() {
+        let ecu = MockEcu::new(true);
+        assert!(matches!(
+            flash_rom(
+                &ecu,
+                &Mazdaspeed6,
+                &test_rom(),
+                &FlashOptions::default(),
+                |_| true
+            ),
+            Err(FlashError::VerificationFailed)
+        ));
+    }
+
+    #[test]
+    fn flash_invalid_rom() {
+        let ecu = MockEcu::new(false);
+        let mut data = test_rom().data().to_vec();
+        data[0] ^= 1;
+        assert!(matches!(
+            flash_rom(
+                &ecu,
+                &CheckedPlatform,
+                &Rom::from(data),
+                &FlashOptions::default(),
+                |_| true
+            ),
+            Err(FlashError::InvalidChecksum(_))
+        ));
+
+        let rom = Rom::from(vec![0; 16]);
+        assert!(matches!(
+            flash_rom(
+                &ecu,
+                &CheckedPlatform,
+                &rom,
+                &FlashOptions::default(),
+                |_| true
+            ),
+            Err(FlashError::Rom(RomError::InvalidSize { .. }))
+        ));
+        assert!(ecu.erased.borrow().is_empty());
+    }
+
+    #[test]
+    fn flash_cancel() {
+        let ecu = MockEcu::new(false);
+        assert!(matches!(
+            flash_rom(
+                &ecu,
+                &Mazdaspeed6,
+                &test_rom(),
+                &FlashOptions::default(),
+                |event| { event != FlashEvent::Erasing }
+            ),
+            Err(FlashError::Cancelled)
+        ));
+        assert!(ecu.erased.borrow().is_empty());
+    }
+
+    #[test]
+    fn flash_invalid_block_size() {
+        let ecu = MockEcu::new(false);
+        let options = FlashOptions {
+            max_block_size: 0,
+            ..Default::default()
+        };
+        assert!(matches!(
+            flash_rom(&ecu, &Mazdaspeed6, &test_rom(), &options, |_| true),
+            Err(FlashError::InvalidBlockSize)
+        ));
+        assert!(ecu.erased.borrow().is_empty());
+
+        // No room for data after the SID and blockSequenceCounter
+        let ecu = MockEcu {
+            max_block_length: 2,
+            ..MockEcu::new(false)
+        };
+        assert!(matches!(
+            flash_rom(
+                &ecu,
+                &Mazdaspeed6,
+                &test_rom(),
+                &FlashOptions::default(),
+                |_| true
+            ),
+            Err(FlashError::InvalidMaxBlockLength(2))
+        ));
+    }
+
+    #[test]
+    fn flash_delta() {
+        let base = test_rom();
+        let ecu = MockEcu::new(false);
+        ecu.memory.borrow_mut().copy_from_slice(base.data());
+
+        // Change a byte in EB1 and EB9, then correct the checksum in EB15
+        let mut data = base.data().to_vec();
+        data[0x2000] ^= 0xFF;
+        data[0x20010] ^= 0xFF;
+        let mut rom = Rom::from(data);
+        rom.fix_checksums(&CheckedPlatform).unwrap();
+
+        let mut read = false;
+        let sectors = flash_rom_delta(
+            &ecu,
+            &CheckedPlatform,
+            &rom,
+            None,
+            &FlashOptions::default(),
+            |event| {
+                read |= matches!(event, FlashEvent::Reading { .. });
+                true
+            },
+        )
+        .unwrap();
+        assert!(read);
+        let expected = vec![0x2000..0x4000, 0x20000..0x40000, 0xE0000..0x100000];
+        assert_eq!(sectors, expected);
+        assert_eq!(*ecu.erased.borrow(), expected);
+        assert_eq!(&ecu.memory.borrow()[..], rom.data());
+
+        // Nothing changed
+        ecu.erased.borrow_mut().clear();
+        let sectors = flash_rom_delta(
+            &ecu,
+            &CheckedPlatform,
+            &rom,
+            Some(&rom),
+            &FlashOptions::default(),
+            |_| true,
+        )
+        .unwrap();
+        assert!(sectors.is_empty());
+        assert!(ecu.erased.borrow().is_empty());
+    }
+
+    #[test]
+    fn flash_delta_base() {
+        let base = test_rom();
+        let ecu = MockEcu::new(false);
+        ecu.memory.borrow_mut().copy_from_slice(base.data());
+
+        let mut data = base.data().to_vec();
+        data[0x2000] ^= 0xFF;
+        let mut rom = Rom::from(data);
+        rom.fix_checksums(&CheckedPlatform).unwrap();
+        let sectors = flash_rom_delta(
+            &ecu,
+            &CheckedPlatform,
+            &rom,
+            Some(&base),
+            &FlashOptions::default(),
+            |_| true,
+        )
+        .unwrap();
+        assert_eq!(sectors, vec![0x2000..0x4000, 0xE0000..0x100000]);
+        assert_eq!(&ecu.memory.borrow()[..], rom.data());
+
+        // Only the flashed sectors are read back
+        assert_eq!(ecu.read.get(), 0x2000 + 0x20000);
+    }
+
+    #[test]
+    fn flash_delta_verification_failed() {
+        let base = test_rom();
+        let ecu = MockEcu::new(true);
+        ecu.memory.borrow_mut().copy_from_slice(base.data());
+
+        let mut data = base.data().to_vec();
+        data[0x2000] ^= 0xFF;
+        let mut rom = Rom::from(data);
+        rom.fix_checksums(&CheckedPlatform).unwrap();
+        assert!(matches!(
+            flash_rom_delta(
+                &ecu,
+                &CheckedPlatform,
+                &rom,
+                Some(&base),
+                &FlashOptions::default(),
+                |_| true
+            ),
+            Err(FlashError::VerificationFailed)
+        ));
+    }
+}
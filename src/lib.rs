@@ -1,27 +1,417 @@
-use std::fs::File;
-use std::io::{Error, Read, SeekFrom};
-use std::ops::{Index, Mul};
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::ops::{Deref, DerefMut, Range};
 use std::path::Path;
-use std::slice::SliceIndex;
 
 pub use byteordered::Endianness;
+use thiserror::Error;
 
+use crate::checksum::{ChecksumRegion, ChecksumResult};
+use crate::numvec::{DataType, NumVecRead, NumVecWrite};
 use crate::platform::Platform;
-use crate::table::{Table, TableData};
+use crate::table::{Axis, AxisTicks, NumVec, Table, TableData};
 
+pub mod checksum;
 pub mod datalink;
+pub mod download;
+pub mod flash;
 pub mod numvec;
 pub mod platform;
+pub mod simulator;
 pub mod table;
 
+#[derive(Error, Debug)]
+pub enum RomError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    /// Occurs when a region extends past the end of the ROM.
+    #[error("region at offset {offset:#X} with length {length} exceeds ROM size {rom_size}")]
+    OutOfBounds {
+        offset: u64,
+        length: usize,
+        rom_size: usize,
+    },
+
+    #[error("unknown axis `{0}`")]
+    UnknownAxis(String),
+
+    /// Occurs when a checksum region ends before it starts or its length is
+    /// not a multiple of 4.
+    #[error("checksum region `{name}` from {start:#X} to {end:#X} is not a whole number of 32-bit words")]
+    InvalidChecksumRegion { name: String, start: u64, end: u64 },
+
+    /// Occurs when the checksum of a region is not placed as its algorithm
+    /// requires.
+    #[error("checksum of region `{name}` at {offset:#X} is misplaced for its algorithm")]
+    MisplacedChecksum { name: String, offset: u64 },
+
+    /// Occurs when table data does not have the dimensions of the table it is written to.
+    #[error("table data is {found:?} (width, height) but table is {expected:?}")]
+    ShapeMismatch {
+        expected: (usize, usize),
+        found: (usize, usize),
+    },
+
+    #[error("table data is {found:?} but table is {expected:?}")]
+    DataTypeMismatch { expected: DataType, found: DataType },
+
+    /// Occurs when a ROM image does not have the length expected by the platform.
+    #[error("ROM is {found} bytes but platform expects {expected} bytes")]
+    InvalidSize { expected: usize, found: usize },
+}
+
+/// Default [`OpenOptions::mmap_threshold`].
+pub const MMAP_THRESHOLD: usize = 16 * 1024 * 1024;
+
+/// Options for [`Rom::open_with`].
+#[derive(Debug, Clone)]
+pub struct OpenOptions {
+    /// Images of at least this many bytes are memory-mapped when the `mmap`
+    /// feature is enabled. Ignored without the feature.
+    pub mmap_threshold: usize,
+}
+
+impl Default for OpenOptions {
+    fn default() -> OpenOptions {
+        OpenOptions {
+            mmap_threshold: MMAP_THRESHOLD,
+        }
+    }
+}
+
+/// Backing storage of a [`Rom`].
+enum RomData {
+    Owned(Vec<u8>),
+
+    /// Private copy-on-write mapping. Writes are never carried through to the file.
+    #[cfg(feature = "mmap")]
+    Mapped(memmap2::MmapMut),
+}
+
+impl Deref for RomData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            RomData::Owned(v) => v,
+            #[cfg(feature = "mmap")]
+            RomData::Mapped(m) => m,
+        }
+    }
+}
+
+impl DerefMut for RomData {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            RomData::Owned(v) => v,
+            #[cfg(feature = "mmap")]
+            RomData::Mapped(m) => m,
+        }
+    }
+}
+
 pub struct Rom {
-    data: Vec<u8>,
+    data: RomData,
+
+    /// Sorted, non-overlapping byte ranges modified since the last call to
+    /// [`Rom::clear_dirty`].
+    dirty: Vec<Range<usize>>,
 }
 
 impl Rom {
+    /// Opens the ROM image at `path` with the default [`OpenOptions`]. The
+    /// file length must equal the ROM length of `platform`.
+    pub fn open<P: Platform, Q: AsRef<Path>>(path: Q, platform: &P) -> Result<Rom, RomError> {
+        Rom::open_with(path, platform, &OpenOptions::default())
+    }
+
+    /// Opens the ROM image at `path`. The file length must equal the ROM
+    /// length of `platform`. With the `mmap` feature, images of at least
+    /// [`OpenOptions::mmap_threshold`] bytes are memory-mapped.
+    pub fn open_with<P: Platform, Q: AsRef<Path>>(
+        path: Q,
+        platform: &P,
+        options: &OpenOptions,
+    ) -> Result<Rom, RomError> {
+        let mut file = File::open(path)?;
+        let found = usize::try_from(file.metadata()?.len()).unwrap_or(usize::MAX);
+        let expected = platform.rom_length();
+        if found != expected {
+            return Err(RomError::InvalidSize { expected, found });
+        }
+
+        #[cfg(feature = "mmap")]
+        {
+            if found >= options.mmap_threshold {
+                // Safety: the file must not be truncated or modified while the
+                // ROM is alive. Truncating it makes reads of the lost pages
+                // fault, and pages that were not written yet would show
+                // changes made by other processes. Writes through the ROM only
+                // touch private copies, and `save` replaces the file instead
+                // of writing to it.
+                let map = unsafe { memmap2::MmapOptions::new().map_copy(&file)? };
+                return Ok(Rom {
+                    data: RomData::Mapped(map),
+                    dirty: Vec::new(),
+                });
+            }
+        }
+
+        #[cfg(not(feature = "mmap"))]
+        let _ = options;
+
+        Ok(file.read_rom(found)?)
+    }
+
+    /// Saves the ROM image to `path`. The image is written to a temporary
+    /// file in the same directory which then replaces `path`, so an
+    /// interrupted save never leaves a partially written image behind.
+    pub fn save<Q: AsRef<Path>>(&self, path: Q) -> Result<(), RomError> {
+        let path = path.as_ref();
+        let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+        file_name.push(".tmp");
+        let tmp_path = path.with_file_name(file_name);
+
+        let res = File::create(&tmp_path).and_then(|mut file| {
+            file.write_all(&self.data)?;
+            file.sync_all()
+        });
+        if let Err(err) = res.and_then(|_| fs::rename(&tmp_path, path)) {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(err.into());
+        }
+        Ok(())
+    }
+
+    /// Returns the raw ROM data.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Returns the byte length of the ROM.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Returns true if the ROM contains no data.
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Returns the byte ranges modified by writes since the last call to
+    /// [`Rom::clear_dirty`]. Ranges are sorted and do not overlap.
+    pub fn dirty_regions(&self) -> &[Range<usize>] {
+        &self.dirty
+    }
+
+    /// Forgets all modified byte ranges, e.g. after the ROM has been flashed.
+    pub fn clear_dirty(&mut self) {
+        self.dirty.clear();
+    }
+
+    /// Returns the byte range at `offset` with `length` bytes, or
+    /// [`RomError::OutOfBounds`] if the region runs past the end of the ROM.
+    fn bounds(&self, offset: u64, length: usize) -> Result<Range<usize>, RomError> {
+        let out_of_bounds = || RomError::OutOfBounds {
+            offset,
+            length,
+            rom_size: self.data.len(),
+        };
+        let start = usize::try_from(offset).map_err(|_| out_of_bounds())?;
+        let end = start.checked_add(length).ok_or_else(out_of_bounds)?;
+        if end > self.data.len() {
+            return Err(out_of_bounds());
+        }
+        Ok(start..end)
+    }
+
+    /// Returns the slice of ROM data at `offset` with `length` bytes.
+    fn region(&self, offset: u64, length: usize) -> Result<&[u8], RomError> {
+        let range = self.bounds(offset, length)?;
+        Ok(&self.data[range])
+    }
+
+    /// Checks that `region` is well formed and lies within the ROM. Returns the
+    /// byte range of the stored checksum.
+    fn checksum_bounds(&self, region: &ChecksumRegion) -> Result<Range<usize>, RomError> {
+        if !region.is_aligned() {
+            return Err(RomError::InvalidChecksumRegion {
+                name: region.name.clone(),
+                start: region.start,
+                end: region.end,
+            });
+        }
+        if !region.is_checksum_placed() {
+            return Err(RomError::MisplacedChecksum {
+                name: region.name.clone(),
+                offset: region.checksum_offset,
+            });
+        }
+        self.bounds(region.start, (region.end - region.start) as usize)?;
+        self.bounds(region.checksum_offset, 4)
+    }
+
+    /// Adds `range` to the dirty regions, merging it with any ranges it
+    /// overlaps or touches.
+    fn mark_dirty(&mut self, range: Range<usize>) {
+        if range.start == range.end {
+            return;
+        }
+        let mut merged = range;
+        let mut dirty = Vec::with_capacity(self.dirty.len() + 1);
+        for r in self.dirty.drain(..) {
+            if r.end < merged.start || r.start > merged.end {
+                dirty.push(r);
+            } else {
+                merged = merged.start.min(r.start)..merged.end.max(r.end);
+            }
+        }
+        let index = dirty
+            .iter()
+            .position(|r| r.start > merged.start)
+            .unwrap_or(dirty.len());
+        dirty.insert(index, merged);
+        self.dirty = dirty;
+    }
+
     /// Returns table.
-    pub fn read_table(&self, table: &Table) -> TableData {
-        unimplemented!()
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use overboost::{Endianness, RomRead};
+    /// use overboost::numvec::DataType;
+    /// use overboost::table::{Interpolation, Table};
+    ///
+    /// let rom = Cursor::new(vec![0, 1, 0, 2]).read_rom(4).unwrap();
+    /// let table = Table {
+    ///     width: 2,
+    ///     height: 1,
+    ///     offset: 0,
+    ///     name: "".to_string(),
+    ///     description: "".to_string(),
+    ///     id: "".to_string(),
+    ///     x_axis_id: None,
+    ///     y_axis_id: None,
+    ///     interpolation: Interpolation::Linear,
+    ///     data_type: DataType::U16,
+    ///     endianness: Endianness::Big,
+    /// };
+    /// let table_data = rom.read_table(&table).unwrap();
+    /// assert_eq!(table_data.get::<u16>(1, 0), 2);
+    /// ```
+    pub fn read_table(&self, table: &Table) -> Result<TableData, RomError> {
+        let mut region = self.region(table.offset, table.byte_size())?;
+        let data = region.read_num_vec(table.data_type, table.endianness, table.size())?;
+        Ok(TableData::new(data, table.width, table.height))
+    }
+
+    /// Writes `table_data` into the ROM at the offset of `table`. The data must
+    /// have the same dimensions and data type as `table`. The written bytes
+    /// are added to the dirty regions.
+    pub fn write_table(&mut self, table: &Table, table_data: &TableData) -> Result<(), RomError> {
+        let found = (table_data.width(), table_data.height());
+        if found != (table.width, table.height) {
+            return Err(RomError::ShapeMismatch {
+                expected: (table.width, table.height),
+                found,
+            });
+        }
+        let data_type = table_data.data().data_type();
+        if data_type != table.data_type {
+            return Err(RomError::DataTypeMismatch {
+                expected: table.data_type,
+                found: data_type,
+            });
+        }
+
+        let range = self.bounds(table.offset, table.byte_size())?;
+        let mut region = &mut self.data[range.clone()];
+        region.write_num_vec(table.endianness, table_data.data())?;
+        self.mark_dirty(range);
+        Ok(())
+    }
+
+    /// Verifies the checksum regions of `platform`. Returns one result per region.
+    pub fn verify_checksums<P: Platform>(
+        &self,
+        platform: &P,
+    ) -> Result<Vec<ChecksumResult>, RomError> {
+        platform
+            .checksums()
+            .into_iter()
+            .map(|region| {
+                self.checksum_bounds(&region)?;
+                Ok(ChecksumResult {
+                    stored: region.stored(&self.data),
+                    computed: region.compute(&self.data),
+                    region,
+                })
+            })
+            .collect()
+    }
+
+    /// Rewrites every invalid checksum of `platform`. Regions are corrected in
+    /// order, so a region may cover the checksum of a preceding region.
+    pub fn fix_checksums<P: Platform>(&mut self, platform: &P) -> Result<(), RomError> {
+        for region in platform.checksums() {
+            let range = self.checksum_bounds(&region)?;
+            let computed = region.compute(&self.data);
+            if region.stored(&self.data) != computed {
+                region.store(&mut self.data, computed);
+                self.mark_dirty(range);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns `length` ticks of `axis`. Ticks stored in memory are read
+    /// from the ROM, linear ticks are evaluated as `F64`.
+    pub fn read_axis(&self, axis: &Axis, length: usize) -> Result<NumVec, RomError> {
+        match axis.ticks {
+            AxisTicks::Memory(offset) => {
+                let mut region = self.region(offset, axis.data_type.byte_size() * length)?;
+                Ok(region.read_num_vec(axis.data_type, axis.endianness, length)?)
+            }
+            AxisTicks::Linear(b, m) => {
+                Ok(NumVec::F64((0..length).map(|x| m * x as f64 + b).collect()))
+            }
+        }
+    }
+
+    /// Resolves the axes of `table` through `platform` and returns the
+    /// (x, y) ticks. The x-axis has `table.width` ticks and the y-axis has
+    /// `table.height` ticks.
+    pub fn read_table_axes<P: Platform>(
+        &self,
+        platform: &P,
+        table: &Table,
+    ) -> Result<(Option<NumVec>, Option<NumVec>), RomError> {
+        let resolve = |id: &Option<String>, length| -> Result<Option<NumVec>, RomError> {
+            match id {
+                Some(id) => {
+                    let axis = platform
+                        .axis(id)
+                        .ok_or_else(|| RomError::UnknownAxis(id.clone()))?;
+                    Ok(Some(self.read_axis(&axis, length)?))
+                }
+                None => Ok(None),
+            }
+        };
+        Ok((
+            resolve(&table.x_axis_id, table.width)?,
+            resolve(&table.y_axis_id, table.height)?,
+        ))
+    }
+}
+
+impl From<Vec<u8>> for Rom {
+    fn from(data: Vec<u8>) -> Rom {
+        Rom {
+            data: RomData::Owned(data),
+            dirty: Vec::new(),
+        }
     }
 }
 
@@ -37,24 +427,348 @@ impl<T> RomRead for T
     fn read_rom(&mut self, size: usize) -> std::io::Result<Rom> {
         let mut data = vec![0; size];
         self.read_exact(&mut data)?;
-        Ok(Rom { data })
+        Ok(Rom::from(data))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::fs::File;
+    use std::io::Cursor;
 
-    use crate::numvec::NumVecRead;
-    use crate::platform::{Mazdaspeed6, Platform};
+    use crate::checksum::ChecksumAlgorithm;
+    use crate::datalink::security::{MazdaAlgorithm, SecurityAlgorithm};
+    use crate::numvec::DataType;
+    use crate::table::Interpolation;
 
     use super::*;
-    use super::table::NumVec;
 
-    #[cfg(unix)]
+    struct TestPlatform;
+
+    impl Platform for TestPlatform {
+        fn name() -> &'static str {
+            "Test"
+        }
+
+        fn id() -> &'static str {
+            "test"
+        }
+
+        fn table(&self, _id: &str) -> Option<Table> {
+            None
+        }
+
+        fn axis(&self, id: &str) -> Option<Axis> {
+            let ticks = match id {
+                "rpm" => AxisTicks::Memory(0),
+                "load" => AxisTicks::Linear(10.0, 0.5),
+                _ => return None,
+            };
+            Some(Axis {
+                id: id.to_string(),
+                name: "".to_string(),
+                description: "".to_string(),
+                ticks,
+                data_type: DataType::U8,
+                endianness: Endianness::Big,
+            })
+        }
+
+        fn rom_length(&self) -> usize {
+            16
+        }
+
+        fn rom_address(&self) -> u32 {
+            0
+        }
+
+        fn download_session(&self) -> u8 {
+            0x85
+        }
+
+        fn security_level(&self) -> u8 {
+            1
+        }
+
+        fn security_algorithm(&self) -> Box<dyn SecurityAlgorithm> {
+            Box::new(MazdaAlgorithm::default())
+        }
+
+        fn checksums(&self) -> Vec<ChecksumRegion> {
+            vec![
+                ChecksumRegion {
+                    name: "data".to_string(),
+                    start: 0,
+                    end: 8,
+                    checksum_offset: 8,
+                    algorithm: ChecksumAlgorithm::Sum32,
+                    endianness: Endianness::Big,
+                },
+                ChecksumRegion {
+                    name: "rom".to_string(),
+                    start: 0,
+                    end: 16,
+                    checksum_offset: 12,
+                    algorithm: ChecksumAlgorithm::Sum32Target(0x5AA5_A55A),
+                    endianness: Endianness::Big,
+                },
+            ]
+        }
+    }
+
+    fn test_table(offset: u64) -> Table {
+        Table {
+            width: 2,
+            height: 2,
+            offset,
+            name: "".to_string(),
+            description: "".to_string(),
+            id: "".to_string(),
+            x_axis_id: Some("rpm".to_string()),
+            y_axis_id: Some("load".to_string()),
+            interpolation: Interpolation::Linear,
+            data_type: DataType::U16,
+            endianness: Endianness::Little,
+        }
+    }
+
+    fn test_rom() -> Rom {
+        Cursor::new((0..16).collect::<Vec<u8>>())
+            .read_rom(16)
+            .unwrap()
+    }
+
+    #[test]
+    fn read_table() {
+        let table_data = test_rom().read_table(&test_table(8)).unwrap();
+        assert_eq!(table_data.get::<u16>(0, 0), 0x0908);
+        assert_eq!(table_data.get::<u16>(1, 0), 0x0B0A);
+        assert_eq!(table_data.get::<u16>(0, 1), 0x0D0C);
+        assert_eq!(table_data.get::<u16>(1, 1), 0x0F0E);
+    }
+
+    #[test]
+    fn read_table_out_of_bounds() {
+        match test_rom().read_table(&test_table(9)) {
+            Err(RomError::OutOfBounds {
+                offset: 9,
+                length: 8,
+                rom_size: 16,
+            }) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert!(matches!(
+            test_rom().read_table(&test_table(u64::MAX)),
+            Err(RomError::OutOfBounds { .. })
+        ));
+    }
+
+    #[test]
+    fn write_table_validation() {
+        let mut rom = test_rom();
+        let table = test_table(0);
+
+        let wide = TableData::new(NumVec::U16(vec![0; 4]), 4, 1);
+        assert!(matches!(
+            rom.write_table(&table, &wide),
+            Err(RomError::ShapeMismatch {
+                expected: (2, 2),
+                found: (4, 1),
+            })
+        ));
+
+        let signed = TableData::new(NumVec::I16(vec![0; 4]), 2, 2);
+        assert!(matches!(
+            rom.write_table(&table, &signed),
+            Err(RomError::DataTypeMismatch {
+                expected: DataType::U16,
+                found: DataType::I16,
+            })
+        ));
+
+        let data = TableData::new(NumVec::U16(vec![0; 4]), 2, 2);
+        assert!(matches!(
+            rom.write_table(&test_table(12), &data),
+            Err(RomError::OutOfBounds { .. })
+        ));
+        assert!(rom.dirty_regions().is_empty());
+        assert_eq!(rom.data(), test_rom().data());
+    }
+
+    #[test]
+    fn dirty_regions() {
+        let mut rom = test_rom();
+        let mut table = test_table(0);
+        table.width = 1;
+        table.height = 1;
+        let data = TableData::new(NumVec::U16(vec![0xFFFF]), 1, 1);
+
+        for &offset in &[8, 2, 12, 4, 10] {
+            table.offset = offset;
+            rom.write_table(&table, &data).unwrap();
+        }
+        assert_eq!(rom.dirty_regions(), &[2..6, 8..14]);
+
+        table.offset = 6;
+        rom.write_table(&table, &data).unwrap();
+        assert_eq!(rom.dirty_regions(), &[Range { start: 2, end: 14 }]);
+
+        rom.clear_dirty();
+        assert!(rom.dirty_regions().is_empty());
+    }
+
+    #[test]
+    fn open_save() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rom.bin");
+        fs::write(&path, (0..16).collect::<Vec<u8>>()).unwrap();
+
+        let mut rom = Rom::open(&path, &TestPlatform).unwrap();
+        assert_eq!(rom.data(), test_rom().data());
+
+        let mut table = test_table(0);
+        table.width = 1;
+        table.height = 1;
+        let data = TableData::new(NumVec::U16(vec![0xFFFF]), 1, 1);
+        rom.write_table(&table, &data).unwrap();
+        rom.save(&path).unwrap();
+
+        let saved = fs::read(&path).unwrap();
+        assert_eq!(&saved[..4], &[0xFF, 0xFF, 2, 3]);
+        // The temporary file is renamed over the target
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        fs::write(&path, [0; 15]).unwrap();
+        assert!(matches!(
+            Rom::open(&path, &TestPlatform),
+            Err(RomError::InvalidSize {
+                expected: 16,
+                found: 15,
+            })
+        ));
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn open_mapped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rom.bin");
+        fs::write(&path, (0..16).collect::<Vec<u8>>()).unwrap();
+
+        let options = OpenOptions { mmap_threshold: 16 };
+        let mut rom = Rom::open_with(&path, &TestPlatform, &options).unwrap();
+        assert!(matches!(rom.data, RomData::Mapped(_)));
+        assert_eq!(rom.data(), test_rom().data());
+
+        // Writes are not carried through to the file until it is saved
+        let mut table = test_table(0);
+        table.width = 1;
+        table.height = 1;
+        let data = TableData::new(NumVec::U16(vec![0xFFFF]), 1, 1);
+        rom.write_table(&table, &data).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), test_rom().data());
+        rom.save(&path).unwrap();
+        assert_eq!(&fs::read(&path).unwrap()[..4], &[0xFF, 0xFF, 2, 3]);
+        assert_eq!(&rom.data()[..4], &[0xFF, 0xFF, 2, 3]);
+
+        let options = OpenOptions { mmap_threshold: 17 };
+        let rom = Rom::open_with(&path, &TestPlatform, &options).unwrap();
+        assert!(matches!(rom.data, RomData::Owned(_)));
+    }
+
+    #[test]
+    fn checksums() {
+        let mut rom = test_rom();
+        let results = rom.verify_checksums(&TestPlatform).unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|r| !r.is_valid()));
+
+        rom.fix_checksums(&TestPlatform).unwrap();
+        assert_eq!(rom.dirty_regions(), &[Range { start: 8, end: 16 }]);
+        let results = rom.verify_checksums(&TestPlatform).unwrap();
+        assert!(results.iter().all(|r| r.is_valid()));
+        assert_eq!(results[0].stored, 0x0001_0203 + 0x0405_0607);
+
+        // Editing a table invalidates the checksums
+        let mut table = test_table(0);
+        table.width = 1;
+        table.height = 1;
+        let data = TableData::new(NumVec::U16(vec![0xFFFF]), 1, 1);
+        rom.write_table(&table, &data).unwrap();
+        assert!(rom
+            .verify_checksums(&TestPlatform)
+            .unwrap()
+            .iter()
+            .all(|r| !r.is_valid()));
+        rom.fix_checksums(&TestPlatform).unwrap();
+        assert!(rom
+            .verify_checksums(&TestPlatform)
+            .unwrap()
+            .iter()
+            .all(|r| r.is_valid()));
+    }
+
+    #[test]
+    fn invalid_checksum_region() {
+        let rom = test_rom();
+        let mut region = TestPlatform.checksums().remove(0);
+        region.end = 6;
+        assert!(matches!(
+            rom.checksum_bounds(&region),
+            Err(RomError::InvalidChecksumRegion {
+                start: 0,
+                end: 6,
+                ..
+            })
+        ));
+        region.start = 8;
+        region.end = 4;
+        assert!(matches!(
+            rom.checksum_bounds(&region),
+            Err(RomError::InvalidChecksumRegion { .. })
+        ));
+
+        // Sum32 checksums must lie outside of the region
+        let mut region = TestPlatform.checksums().remove(0);
+        region.checksum_offset = 4;
+        assert!(matches!(
+            rom.checksum_bounds(&region),
+            Err(RomError::MisplacedChecksum { offset: 4, .. })
+        ));
+
+        // Sum32Target checksums must lie inside of the region
+        let mut region = TestPlatform.checksums().remove(1);
+        region.checksum_offset = 0;
+        region.start = 4;
+        assert!(matches!(
+            rom.checksum_bounds(&region),
+            Err(RomError::MisplacedChecksum { offset: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn read_table_axes() {
+        let (x, y) = test_rom()
+            .read_table_axes(&TestPlatform, &test_table(0))
+            .unwrap();
+        let x = x.unwrap();
+        assert_eq!(x.len(), 2);
+        assert_eq!(x.get::<u8>(1), 1);
+        let y = y.unwrap();
+        assert_eq!(y.get::<f64>(0), 10.0);
+        assert_eq!(y.get::<f64>(1), 10.5);
+
+        let mut table = test_table(0);
+        table.x_axis_id = Some("boost".to_string());
+        assert!(matches!(
+            test_rom().read_table_axes(&TestPlatform, &table),
+            Err(RomError::UnknownAxis(_))
+        ));
+    }
+
+    #[cfg(feature = "socketcan-datalink")]
     #[test]
     fn socketcan() {
         use socketcan::CANSocket;
-        let socket = CANSocket::open("vcan0").unwrap();
+        let _socket = CANSocket::open("vcan0").unwrap();
     }
 }
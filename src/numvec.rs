@@ -1,11 +1,11 @@
-use std::io::{Error, Read, Write};
+use std::io::{Read, Write};
 
 use byteordered::{ByteOrdered, Endianness};
-use byteordered::byteorder::WriteBytesExt;
 
 use crate::table::NumVec;
 
 /// DataType for table data
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum DataType {
     I8,
     U8,
@@ -144,7 +144,7 @@ impl<T> NumVecRead for T
     ///
     /// let mut file = File::open("rom.bin").unwrap();
     /// // Read 8 32 bit integers
-    /// let table_data = file.read_numvec(DataType::I32, Endianness::Big, 8);
+    /// let table_data = file.read_num_vec(DataType::I32, Endianness::Big, 8);
     /// ```
     fn read_num_vec(
         &mut self,
@@ -162,71 +162,71 @@ impl<T> NumVecRead for T
         let data = match data_type {
             DataType::I8 => {
                 let mut v = vec![0; length];
-                for i in 0..length {
-                    v[i] = rd.read_i8()?;
+                for n in v.iter_mut() {
+                    *n = rd.read_i8()?;
                 }
                 NumVec::I8(v)
             }
             DataType::U8 => {
                 let mut v = vec![0; length];
-                for i in 0..length {
-                    v[i] = rd.read_u8()?;
+                for n in v.iter_mut() {
+                    *n = rd.read_u8()?;
                 }
                 NumVec::U8(v)
             }
             DataType::I16 => {
                 let mut v = vec![0; length];
-                for i in 0..length {
-                    v[i] = rd.read_i16()?;
+                for n in v.iter_mut() {
+                    *n = rd.read_i16()?;
                 }
                 NumVec::I16(v)
             }
             DataType::U16 => {
                 let mut v = vec![0; length];
-                for i in 0..length {
-                    v[i] = rd.read_u16()?;
+                for n in v.iter_mut() {
+                    *n = rd.read_u16()?;
                 }
                 NumVec::U16(v)
             }
             DataType::I32 => {
                 let mut v = vec![0; length];
-                for i in 0..length {
-                    v[i] = rd.read_i32()?;
+                for n in v.iter_mut() {
+                    *n = rd.read_i32()?;
                 }
                 NumVec::I32(v)
             }
             DataType::U32 => {
                 let mut v = vec![0; length];
-                for i in 0..length {
-                    v[i] = rd.read_u32()?;
+                for n in v.iter_mut() {
+                    *n = rd.read_u32()?;
                 }
                 NumVec::U32(v)
             }
             DataType::I64 => {
                 let mut v = vec![0; length];
-                for i in 0..length {
-                    v[i] = rd.read_i64()?;
+                for n in v.iter_mut() {
+                    *n = rd.read_i64()?;
                 }
                 NumVec::I64(v)
             }
             DataType::U64 => {
                 let mut v = vec![0; length];
-                for i in 0..length {
-                    v[i] = rd.read_u64()?;
+                for n in v.iter_mut() {
+                    *n = rd.read_u64()?;
                 }
                 NumVec::U64(v)
             }
             DataType::F32 => {
                 let mut v = vec![0_f32; length];
-                for i in 0..length {
-                    v[i] = rd.read_f32()?;
+                for n in v.iter_mut() {
+                    *n = rd.read_f32()?;
                 }
                 NumVec::F32(v)
             }
             DataType::F64 => {
                 let mut v = vec![0_f64; length];
-                for i in 0..length {
-                    v[i] = rd.read_f64()?;
+                for n in v.iter_mut() {
+                    *n = rd.read_f64()?;
                 }
                 NumVec::F64(v)
             }
@@ -1,6 +1,100 @@
-use crate::table::Table;
+use std::ops::Range;
 
-pub trait Download {}
+use crate::checksum::ChecksumRegion;
+use crate::datalink::security::{MazdaAlgorithm, SecurityAlgorithm};
+use crate::datalink::uds::{MemoryAddressFormat, UdsError, UdsInterface, UDS_ROUTINE_ERASE_MEMORY};
+use crate::download::{read_block, DownloadOptions};
+use crate::table::{Axis, Table};
+use crate::Rom;
+
+/// RoutineControl routine started after programming, e.g. a checksum or
+/// programming dependency check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Routine {
+    /// routineIdentifier
+    pub id: u16,
+
+    /// routineControlOptionRecord sent with the start request
+    pub option: Vec<u8>,
+
+    /// routineStatusRecord returned when the routine succeeds
+    pub expected_status: Vec<u8>,
+}
+
+/// Flashing support for a platform. Used by [`crate::flash::flash_rom`].
+pub trait Download: Platform {
+    /// Returns the DiagnosticSessionControl session type used to flash the ROM.
+    fn programming_session(&self) -> u8;
+
+    /// Returns the routineIdentifier of the routine that erases memory.
+    /// Defaults to eraseMemory (0xFF00).
+    fn erase_routine(&self) -> u16 {
+        UDS_ROUTINE_ERASE_MEMORY
+    }
+
+    /// Returns the address and size format of RequestDownload and the erase
+    /// routine. Defaults to the platform's
+    /// [`memory_address_format`](Platform::memory_address_format).
+    fn download_address_format(&self) -> MemoryAddressFormat {
+        self.memory_address_format()
+    }
+
+    /// Erases `length` bytes of flash memory at `address`. By default, the
+    /// erase routine is started with the address and length encoded in the
+    /// [`download_address_format`](Download::download_address_format).
+    fn erase<U: UdsInterface + ?Sized>(
+        &self,
+        uds: &U,
+        address: u32,
+        length: u32,
+    ) -> Result<(), UdsError> {
+        let mut option = Vec::with_capacity(9);
+        self.download_address_format()
+            .encode(address, length, &mut option)?;
+        uds.start_routine(self.erase_routine(), &option)?;
+        Ok(())
+    }
+
+    /// Returns the routines started after all regions are programmed. Each
+    /// must return its expected status. Defaults to none.
+    fn check_routines(&self) -> Vec<Routine> {
+        Vec::new()
+    }
+
+    /// Returns the erase sectors of the flash memory as ROM offsets. Sectors
+    /// are sorted, do not overlap and cover the entire ROM.
+    fn sectors(&self) -> Vec<Range<usize>>;
+
+    /// Returns true if the ECU memory in `range` matches `rom` after flashing.
+    /// `range` is a ROM offset. By default, the range is read back and compared.
+    fn verify<U: UdsInterface + ?Sized>(
+        &self,
+        uds: &U,
+        rom: &Rom,
+        range: Range<usize>,
+    ) -> Result<bool, UdsError> {
+        let options = DownloadOptions {
+            retries: 0,
+            ..Default::default()
+        };
+        let block_size = options.block_size as usize;
+        let start = range.start;
+        for (i, expected) in rom.data()[range].chunks(block_size).enumerate() {
+            let address = self.rom_address() + (start + i * block_size) as u32;
+            let block = read_block(
+                uds,
+                self.memory_address_format(),
+                address,
+                expected.len() as u32,
+                &options,
+            )?;
+            if block != expected {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
 
 /// A platform.
 pub trait Platform {
@@ -25,8 +119,33 @@ pub trait Platform {
     /// Searches for table with `id`.
     fn table(&self, id: &str) -> Option<Table>;
 
+    /// Searches for axis with `id`.
+    fn axis(&self, id: &str) -> Option<Axis>;
+
     /// Returns byte length of ROM.
     fn rom_length(&self) -> usize;
+
+    /// Returns the address of the first byte of the ROM in ECU memory.
+    fn rom_address(&self) -> u32;
+
+    /// Returns the address and size format the ECU expects in memory
+    /// services. Defaults to a 4-byte address and a 4-byte size.
+    fn memory_address_format(&self) -> MemoryAddressFormat {
+        MemoryAddressFormat::default()
+    }
+
+    /// Returns the DiagnosticSessionControl session type used to read the ROM.
+    fn download_session(&self) -> u8;
+
+    /// Returns the SecurityAccess level that must be unlocked to read the ROM.
+    fn security_level(&self) -> u8;
+
+    /// Returns the algorithm used to unlock the ECU.
+    fn security_algorithm(&self) -> Box<dyn SecurityAlgorithm>;
+
+    /// Returns the checksummed regions of the ROM in the order they must be
+    /// verified and corrected.
+    fn checksums(&self) -> Vec<ChecksumRegion>;
 }
 
 pub struct Mazdaspeed6;
@@ -40,11 +159,69 @@ impl Platform for Mazdaspeed6 {
         "mazdaspeed6"
     }
 
-    fn table(&self, id: &str) -> Option<Table> {
+    fn table(&self, _id: &str) -> Option<Table> {
+        None
+    }
+
+    fn axis(&self, _id: &str) -> Option<Axis> {
         None
     }
 
     fn rom_length(&self) -> usize {
-        1024 * 1024 * 1024
+        1024 * 1024
+    }
+
+    fn rom_address(&self) -> u32 {
+        0
+    }
+
+    /// ReadMemoryByAddress takes a 4-byte address and a 2-byte size without
+    /// an addressAndLengthFormatIdentifier.
+    fn memory_address_format(&self) -> MemoryAddressFormat {
+        // 4 and 2 bytes are valid sizes
+        MemoryAddressFormat::without_alfid(4, 2).unwrap()
+    }
+
+    fn download_session(&self) -> u8 {
+        0x87
+    }
+
+    fn security_level(&self) -> u8 {
+        1
+    }
+
+    fn security_algorithm(&self) -> Box<dyn SecurityAlgorithm> {
+        Box::new(MazdaAlgorithm::default())
+    }
+
+    /// No checksum regions are described yet. The checksum layout of this
+    /// ROM has not been verified against a documented source or a stock
+    /// image, and a wrong layout would make [`Rom::fix_checksums`] overwrite
+    /// ROM data, so checksums are neither verified nor corrected.
+    fn checksums(&self) -> Vec<ChecksumRegion> {
+        Vec::new()
+    }
+}
+
+impl Download for Mazdaspeed6 {
+    fn programming_session(&self) -> u8 {
+        0x85
+    }
+
+    /// Sectors are up to 128 KiB, so downloads use a 4-byte address and a
+    /// 4-byte size.
+    fn download_address_format(&self) -> MemoryAddressFormat {
+        MemoryAddressFormat::default()
+    }
+
+    /// SH7058 erase blocks: 8 KiB EB0-EB7, 64 KiB EB8 and 128 KiB EB9-EB15.
+    fn sectors(&self) -> Vec<Range<usize>> {
+        let mut sectors = Vec::with_capacity(16);
+        let mut start = 0;
+        for &length in [0x2000; 8].iter().chain(&[0x10000]).chain(&[0x20000; 7]) {
+            sectors.push(start..start + length);
+            start += length;
+        }
+        sectors
     }
 }
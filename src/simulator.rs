@@ -0,0 +1,1128 @@
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::datalink::dtc::{dtc_code, DTC_GROUP_ALL};
+use crate::datalink::isotp::{Isotp, IsotpError};
+use crate::datalink::security::SecurityAlgorithm;
+use crate::datalink::session::DEFAULT_SESSION;
+use crate::datalink::uds::{
+    CommunicationControlType, DtcSettingType, MemoryAddressFormat, NegativeResponseCode as Nrc,
+    UDS_DTC_BY_STATUS_MASK, UDS_DTC_NUMBER_BY_STATUS_MASK, UDS_DTC_SNAPSHOT_BY_DTC_NUMBER,
+    UDS_DTC_SNAPSHOT_IDENTIFICATION, UDS_REQ_CLEARDTC, UDS_REQ_COMMUNICATIONCONTROL,
+    UDS_REQ_CONTROLDTCSETTING, UDS_REQ_ECURESET, UDS_REQ_READDATABYID, UDS_REQ_READDTC,
+    UDS_REQ_READMEM, UDS_REQ_REQUESTDOWNLOAD, UDS_REQ_REQUESTUPLOAD, UDS_REQ_ROUTINECONTROL,
+    UDS_REQ_SECURITY, UDS_REQ_SESSION, UDS_REQ_TESTERPRESENT, UDS_REQ_TRANSFERDATA,
+    UDS_REQ_TRANSFEREXIT, UDS_REQ_WRITEDATABYID, UDS_REQ_WRITEMEM, UDS_SUPPRESS_POSITIVE_RESPONSE,
+};
+use crate::Rom;
+
+/// Transfer started by RequestDownload or RequestUpload.
+struct Transfer {
+    /// True if data is sent by the ECU
+    upload: bool,
+    /// ROM offset of the next TransferData block
+    offset: usize,
+    remaining: usize,
+    sequence: u8,
+}
+
+/// Response-pending behaviour configured for a service.
+#[derive(Copy, Clone)]
+struct Pending {
+    count: usize,
+    delay: Duration,
+}
+
+/// ActiveDiagnosticSessionDataIdentifier
+const DID_ACTIVE_SESSION: u16 = 0xF186;
+
+/// DTC status bits supported by the ECU
+const DTC_STATUS_AVAILABILITY_MASK: u8 = 0xFF;
+
+/// DTCFormatIdentifier for ISO 14229-1 DTCs
+const DTC_FORMAT_ISO14229: u8 = 0x01;
+
+/// Simulated UDS ECU backed by a [`Rom`] image.
+///
+/// Answers DiagnosticSessionControl, ECUReset, SecurityAccess,
+/// CommunicationControl, ControlDTCSetting, TesterPresent,
+/// ReadMemoryByAddress, WriteMemoryByAddress, ReadDataByIdentifier,
+/// WriteDataByIdentifier, ReadDTCInformation,
+/// ClearDiagnosticInformation, RequestDownload, RequestUpload, TransferData,
+/// RequestTransferExit and the eraseMemory (0xFF00) routine. Memory services
+/// require security access. Non-default sessions fall back to the default
+/// session when no request is received within the S3 timeout. Negative
+/// responses and responsePending (0x78) delays can be configured per service.
+pub struct SimulatedEcu {
+    rom: Rom,
+    base_address: u32,
+    algorithm: Box<dyn SecurityAlgorithm + Send>,
+    seed: Vec<u8>,
+    session: u8,
+    /// Level whose seed was requested and awaits a key
+    seed_level: Option<u8>,
+    unlocked: Option<u8>,
+    data_identifiers: HashMap<u16, Vec<u8>>,
+    /// Stored DTCs and their status bytes
+    dtcs: Vec<(u32, u8)>,
+    /// Encoded snapshot records by DTC and record number
+    snapshots: BTreeMap<(u32, u8), Vec<u8>>,
+    transfer: Option<Transfer>,
+    /// Format of ReadMemoryByAddress and WriteMemoryByAddress
+    memory_address_format: MemoryAddressFormat,
+    /// Format of RequestDownload, RequestUpload and eraseMemory
+    download_address_format: MemoryAddressFormat,
+    max_block_length: u16,
+    negative_responses: HashMap<u8, Nrc>,
+    pending: HashMap<u8, Pending>,
+    s3_timeout: Duration,
+    last_request: Instant,
+    communication: CommunicationControlType,
+    dtc_setting: DtcSettingType,
+    resets: usize,
+}
+
+impl SimulatedEcu {
+    /// Creates an ECU in the default session with `rom` mapped at address 0.
+    /// Keys are checked with `algorithm`.
+    pub fn new(rom: Rom, algorithm: Box<dyn SecurityAlgorithm + Send>) -> SimulatedEcu {
+        SimulatedEcu {
+            rom,
+            base_address: 0,
+            algorithm,
+            seed: vec![0x12, 0x34, 0x56],
+            session: DEFAULT_SESSION,
+            seed_level: None,
+            unlocked: None,
+            data_identifiers: HashMap::new(),
+            dtcs: Vec::new(),
+            snapshots: BTreeMap::new(),
+            transfer: None,
+            memory_address_format: MemoryAddressFormat::default(),
+            download_address_format: MemoryAddressFormat::default(),
+            max_block_length: 0x802,
+            negative_responses: HashMap::new(),
+            pending: HashMap::new(),
+            s3_timeout: Duration::from_secs(5),
+            last_request: Instant::now(),
+            communication: CommunicationControlType::EnableRxAndTx,
+            dtc_setting: DtcSettingType::On,
+            resets: 0,
+        }
+    }
+
+    /// Returns the memory of the ECU.
+    pub fn rom(&self) -> &Rom {
+        &self.rom
+    }
+
+    /// Returns the active diagnostic session.
+    pub fn session(&self) -> u8 {
+        self.session
+    }
+
+    /// Returns the communication state set by CommunicationControl.
+    pub fn communication(&self) -> CommunicationControlType {
+        self.communication
+    }
+
+    /// Returns the DTC setting state set by ControlDTCSetting.
+    pub fn dtc_setting(&self) -> DtcSettingType {
+        self.dtc_setting
+    }
+
+    /// Returns the number of times the ECU was reset with ECUReset.
+    pub fn resets(&self) -> usize {
+        self.resets
+    }
+
+    /// Maps the ROM at `address`.
+    pub fn set_base_address(&mut self, address: u32) {
+        self.base_address = address;
+    }
+
+    /// Sets the seed sent for SecurityAccess requestSeed.
+    pub fn set_seed(&mut self, seed: Vec<u8>) {
+        self.seed = seed;
+    }
+
+    /// Sets the value returned by ReadDataByIdentifier for `id`.
+    pub fn set_data_identifier(&mut self, id: u16, data: Vec<u8>) {
+        self.data_identifiers.insert(id, data);
+    }
+
+    /// Stores `dtc` with status byte `status`, replacing the status if the
+    /// DTC is already stored.
+    pub fn set_dtc(&mut self, dtc: u32, status: u8) {
+        match self.dtcs.iter_mut().find(|(code, _)| *code == dtc) {
+            Some(stored) => stored.1 = status,
+            None => self.dtcs.push((dtc, status)),
+        }
+    }
+
+    /// Stores snapshot record `record_number` of `dtc` containing the data
+    /// identifiers and values in `data`. The DTC must be stored.
+    pub fn set_dtc_snapshot(&mut self, dtc: u32, record_number: u8, data: Vec<(u16, Vec<u8>)>) {
+        let mut record = vec![data.len() as u8];
+        for (id, value) in data {
+            record.extend_from_slice(&id.to_be_bytes());
+            record.extend_from_slice(&value);
+        }
+        self.snapshots.insert((dtc, record_number), record);
+    }
+
+    /// Sets the address and size format of ReadMemoryByAddress and
+    /// WriteMemoryByAddress. With an addressAndLengthFormatIdentifier, the
+    /// widths are taken from each request instead of `format`.
+    pub fn set_memory_address_format(&mut self, format: MemoryAddressFormat) {
+        self.memory_address_format = format;
+    }
+
+    /// Sets the address and size format of RequestDownload, RequestUpload
+    /// and the eraseMemory routine, like
+    /// [`set_memory_address_format`](Self::set_memory_address_format).
+    pub fn set_download_address_format(&mut self, format: MemoryAddressFormat) {
+        self.download_address_format = format;
+    }
+
+    /// Sets maxNumberOfBlockLength returned by RequestDownload.
+    pub fn set_max_block_length(&mut self, length: u16) {
+        self.max_block_length = length;
+    }
+
+    /// Makes every request for `sid` fail with `code`, or removes the
+    /// override if `code` is `None`.
+    pub fn set_negative_response(&mut self, sid: u8, code: Option<Nrc>) {
+        match code {
+            Some(code) => self.negative_responses.insert(sid, code),
+            None => self.negative_responses.remove(&sid),
+        };
+    }
+
+    /// Sends `count` responsePending responses, each after `delay`, before
+    /// answering requests for `sid`.
+    pub fn set_response_pending(&mut self, sid: u8, count: usize, delay: Duration) {
+        self.pending.insert(sid, Pending { count, delay });
+    }
+
+    /// Sets the time after which a non-default session returns to the
+    /// default session if no request is received. Defaults to 5 seconds.
+    pub fn set_s3_timeout(&mut self, timeout: Duration) {
+        self.s3_timeout = timeout;
+    }
+
+    /// Receives a single request from `isotp` and sends the response.
+    pub fn serve_one<I: Isotp + ?Sized>(&mut self, isotp: &I) -> Result<(), IsotpError> {
+        let request = isotp.read_isotp()?;
+        if let Some(&sid) = request.first() {
+            if let Some(pending) = self.pending.get(&sid).copied() {
+                for _ in 0..pending.count {
+                    thread::sleep(pending.delay);
+                    isotp.write_isotp(&[0x7F, sid, Nrc::ResponsePending.into()])?;
+                }
+            }
+        }
+        let response = self.handle(&request);
+        if response.is_empty() {
+            return Ok(());
+        }
+        isotp.write_isotp(&response)
+    }
+
+    /// Serves requests from `isotp` until `stop` is set. Read timeouts are
+    /// ignored; other errors stop the server.
+    pub fn serve<I: Isotp + ?Sized>(
+        &mut self,
+        isotp: &I,
+        stop: &AtomicBool,
+    ) -> Result<(), IsotpError> {
+        while !stop.load(Ordering::Relaxed) {
+            match self.serve_one(isotp) {
+                Err(err) if err.is_timeout() => {}
+                res => res?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles a request and returns the final response. Returns an empty
+    /// response if the positive response is suppressed.
+    pub fn handle(&mut self, request: &[u8]) -> Vec<u8> {
+        if self.session != DEFAULT_SESSION && self.last_request.elapsed() >= self.s3_timeout {
+            self.session_control(&[DEFAULT_SESSION]).unwrap();
+        }
+        self.last_request = Instant::now();
+
+        let sid = match request.first() {
+            Some(&sid) => sid,
+            None => return vec![0x7F, 0x00, Nrc::IncorrectMessageLength.into()],
+        };
+        if let Some(&code) = self.negative_responses.get(&sid) {
+            return vec![0x7F, sid, code.into()];
+        }
+
+        let mut data = request[1..].to_vec();
+        let mut suppress = false;
+        if has_sub_function(sid) && !data.is_empty() {
+            suppress = data[0] & UDS_SUPPRESS_POSITIVE_RESPONSE != 0;
+            data[0] &= !UDS_SUPPRESS_POSITIVE_RESPONSE;
+        }
+        let data = &data[..];
+        let res = match sid {
+            UDS_REQ_SESSION => self.session_control(data),
+            UDS_REQ_ECURESET => self.ecu_reset(data),
+            UDS_REQ_COMMUNICATIONCONTROL => self.communication_control(data),
+            UDS_REQ_CONTROLDTCSETTING => self.control_dtc_setting(data),
+            UDS_REQ_SECURITY => self.security_access(data),
+            UDS_REQ_TESTERPRESENT => self.tester_present(data),
+            UDS_REQ_READMEM => self.read_memory(data),
+            UDS_REQ_READDATABYID => self.read_data_by_identifier(data),
+            UDS_REQ_WRITEDATABYID => self.write_data_by_identifier(data),
+            UDS_REQ_WRITEMEM => self.write_memory(data),
+            UDS_REQ_READDTC => self.read_dtc_information(data),
+            UDS_REQ_CLEARDTC => self.clear_dtcs(data),
+            UDS_REQ_REQUESTDOWNLOAD => self.request_transfer(data, false),
+            UDS_REQ_REQUESTUPLOAD => self.request_transfer(data, true),
+            UDS_REQ_TRANSFERDATA => self.transfer_data(data),
+            UDS_REQ_TRANSFEREXIT => self.transfer_exit(),
+            UDS_REQ_ROUTINECONTROL => self.routine_control(data),
+            _ => Err(Nrc::ServiceNotSupported),
+        };
+        match res {
+            Ok(_) if suppress => Vec::new(),
+            Ok(mut response) => {
+                response.insert(0, sid + 0x40);
+                response
+            }
+            Err(code) => vec![0x7F, sid, code.into()],
+        }
+    }
+
+    fn session_control(&mut self, data: &[u8]) -> Result<Vec<u8>, Nrc> {
+        if data.len() != 1 {
+            return Err(Nrc::IncorrectMessageLength);
+        }
+        self.session = data[0];
+        self.seed_level = None;
+        self.unlocked = None;
+        self.transfer = None;
+        if self.session == DEFAULT_SESSION {
+            self.communication = CommunicationControlType::EnableRxAndTx;
+            self.dtc_setting = DtcSettingType::On;
+        }
+        // P2 = 50 ms, P2* = 5000 ms
+        Ok(vec![data[0], 0x00, 0x32, 0x01, 0xF4])
+    }
+
+    fn ecu_reset(&mut self, data: &[u8]) -> Result<Vec<u8>, Nrc> {
+        match data {
+            [reset_type @ 0x01..=0x03] => {
+                self.session_control(&[DEFAULT_SESSION])?;
+                self.resets += 1;
+                Ok(vec![*reset_type])
+            }
+            // powerDownTime of 15 seconds
+            [0x04] => Ok(vec![0x04, 0x0F]),
+            [0x05] => Ok(vec![0x05]),
+            [_] => Err(Nrc::SubFunctionNotSupported),
+            _ => Err(Nrc::IncorrectMessageLength),
+        }
+    }
+
+    fn communication_control(&mut self, data: &[u8]) -> Result<Vec<u8>, Nrc> {
+        if data.len() != 2 {
+            return Err(Nrc::IncorrectMessageLength);
+        }
+        let communication = match data[0] {
+            0x00 => CommunicationControlType::EnableRxAndTx,
+            0x01 => CommunicationControlType::EnableRxAndDisableTx,
+            0x02 => CommunicationControlType::DisableRxAndEnableTx,
+            0x03 => CommunicationControlType::DisableRxAndTx,
+            _ => return Err(Nrc::SubFunctionNotSupported),
+        };
+        if !(0x01..=0x03).contains(&data[1]) {
+            return Err(Nrc::RequestOutOfRange);
+        }
+        if self.session == DEFAULT_SESSION {
+            return Err(Nrc::ServiceNotSupportedInActiveSession);
+        }
+        self.communication = communication;
+        Ok(vec![data[0]])
+    }
+
+    fn control_dtc_setting(&mut self, data: &[u8]) -> Result<Vec<u8>, Nrc> {
+        let setting = match data {
+            [0x01] => DtcSettingType::On,
+            [0x02] => DtcSettingType::Off,
+            [_] => return Err(Nrc::SubFunctionNotSupported),
+            _ => return Err(Nrc::IncorrectMessageLength),
+        };
+        if self.session == DEFAULT_SESSION {
+            return Err(Nrc::ServiceNotSupportedInActiveSession);
+        }
+        self.dtc_setting = setting;
+        Ok(vec![data[0]])
+    }
+
+    fn tester_present(&mut self, data: &[u8]) -> Result<Vec<u8>, Nrc> {
+        match data {
+            [0x00] => Ok(vec![0x00]),
+            [_] => Err(Nrc::SubFunctionNotSupported),
+            _ => Err(Nrc::IncorrectMessageLength),
+        }
+    }
+
+    fn security_access(&mut self, data: &[u8]) -> Result<Vec<u8>, Nrc> {
+        let level = *data.first().ok_or(Nrc::IncorrectMessageLength)?;
+        if level % 2 == 1 {
+            // requestSeed
+            if self.unlocked == Some(level) {
+                let mut response = vec![level];
+                response.resize(self.seed.len() + 1, 0);
+                return Ok(response);
+            }
+            self.seed_level = Some(level);
+            let mut response = vec![level];
+            response.extend_from_slice(&self.seed);
+            return Ok(response);
+        }
+
+        // sendKey
+        let seed_level = level.wrapping_sub(1);
+        if self.seed_level.take() != Some(seed_level) {
+            return Err(Nrc::RequestSequenceError);
+        }
+        match self.algorithm.compute_key(seed_level, &self.seed) {
+            Some(key) if key == data[1..] => {
+                self.unlocked = Some(seed_level);
+                Ok(vec![level])
+            }
+            _ => Err(Nrc::InvalidKey),
+        }
+    }
+
+    /// Returns the ROM range of `length` bytes at memory `address`.
+    fn memory_range(&self, address: u64, length: u64) -> Result<(usize, usize), Nrc> {
+        let start = address
+            .checked_sub(self.base_address as u64)
+            .ok_or(Nrc::RequestOutOfRange)?;
+        let end = start.checked_add(length).ok_or(Nrc::RequestOutOfRange)?;
+        if end > self.rom.len() as u64 {
+            return Err(Nrc::RequestOutOfRange);
+        }
+        Ok((start as usize, end as usize))
+    }
+
+    fn require_security(&self) -> Result<(), Nrc> {
+        if self.unlocked.is_none() {
+            return Err(Nrc::SecurityAccessDenied);
+        }
+        Ok(())
+    }
+
+    fn read_memory(&mut self, data: &[u8]) -> Result<Vec<u8>, Nrc> {
+        let (start, end) = self.parse_memory_range(self.memory_address_format, data)?;
+        self.require_security()?;
+        Ok(self.rom.data()[start..end].to_vec())
+    }
+
+    fn read_data_by_identifier(&mut self, data: &[u8]) -> Result<Vec<u8>, Nrc> {
+        if data.len() != 2 {
+            return Err(Nrc::IncorrectMessageLength);
+        }
+        let id = read_be(data) as u16;
+        let mut response = data.to_vec();
+        match self.data_identifiers.get(&id) {
+            Some(value) => response.extend_from_slice(value),
+            None if id == DID_ACTIVE_SESSION => response.push(self.session),
+            None => return Err(Nrc::RequestOutOfRange),
+        }
+        Ok(response)
+    }
+
+    /// Replaces the value of an existing data identifier.
+    fn write_data_by_identifier(&mut self, data: &[u8]) -> Result<Vec<u8>, Nrc> {
+        if data.len() < 3 {
+            return Err(Nrc::IncorrectMessageLength);
+        }
+        let id = read_be(&data[..2]) as u16;
+        let value = self
+            .data_identifiers
+            .get_mut(&id)
+            .ok_or(Nrc::RequestOutOfRange)?;
+        *value = data[2..].to_vec();
+        Ok(data[..2].to_vec())
+    }
+
+    fn write_memory(&mut self, data: &[u8]) -> Result<Vec<u8>, Nrc> {
+        let header_len = address_and_length_size(self.memory_address_format, data)?;
+        let (start, end) =
+            self.parse_memory_range(self.memory_address_format, &data[..header_len])?;
+        if data.len() - header_len != end - start {
+            return Err(Nrc::IncorrectMessageLength);
+        }
+        self.require_security()?;
+        self.rom.data[start..end].copy_from_slice(&data[header_len..]);
+        self.rom.mark_dirty(start..end);
+        Ok(data[..header_len].to_vec())
+    }
+
+    fn read_dtc_information(&mut self, data: &[u8]) -> Result<Vec<u8>, Nrc> {
+        let report_type = *data.first().ok_or(Nrc::IncorrectMessageLength)?;
+        let data = &data[1..];
+        let mut response = vec![report_type];
+        match report_type {
+            UDS_DTC_NUMBER_BY_STATUS_MASK | UDS_DTC_BY_STATUS_MASK => {
+                if data.len() != 1 {
+                    return Err(Nrc::IncorrectMessageLength);
+                }
+                let dtcs = self.dtcs.iter().filter(|(_, status)| status & data[0] != 0);
+                response.push(DTC_STATUS_AVAILABILITY_MASK);
+                if report_type == UDS_DTC_NUMBER_BY_STATUS_MASK {
+                    response.push(DTC_FORMAT_ISO14229);
+                    response.extend_from_slice(&(dtcs.count() as u16).to_be_bytes());
+                } else {
+                    for (dtc, status) in dtcs {
+                        response.extend_from_slice(&dtc.to_be_bytes()[1..]);
+                        response.push(*status);
+                    }
+                }
+            }
+            UDS_DTC_SNAPSHOT_IDENTIFICATION => {
+                if !data.is_empty() {
+                    return Err(Nrc::IncorrectMessageLength);
+                }
+                for (dtc, record_number) in self.snapshots.keys() {
+                    response.extend_from_slice(&dtc.to_be_bytes()[1..]);
+                    response.push(*record_number);
+                }
+            }
+            UDS_DTC_SNAPSHOT_BY_DTC_NUMBER => {
+                if data.len() != 4 {
+                    return Err(Nrc::IncorrectMessageLength);
+                }
+                let dtc = dtc_code(data);
+                let status = self
+                    .dtcs
+                    .iter()
+                    .find(|(code, _)| *code == dtc)
+                    .ok_or(Nrc::RequestOutOfRange)?
+                    .1;
+                response.extend_from_slice(&data[..3]);
+                response.push(status);
+                // Record number 0xFF requests all records
+                let records = self.snapshots.iter().filter(|((code, number), _)| {
+                    *code == dtc && (data[3] == 0xFF || *number == data[3])
+                });
+                for ((_, number), record) in records {
+                    response.push(*number);
+                    response.extend_from_slice(record);
+                }
+            }
+            _ => return Err(Nrc::SubFunctionNotSupported),
+        }
+        Ok(response)
+    }
+
+    fn clear_dtcs(&mut self, data: &[u8]) -> Result<Vec<u8>, Nrc> {
+        if data.len() != 3 {
+            return Err(Nrc::IncorrectMessageLength);
+        }
+        let group = dtc_code(data);
+        let selected = |dtc: u32| group == DTC_GROUP_ALL || dtc == group;
+        if !self.dtcs.iter().any(|(dtc, _)| selected(*dtc)) && group != DTC_GROUP_ALL {
+            return Err(Nrc::RequestOutOfRange);
+        }
+        self.dtcs.retain(|(dtc, _)| !selected(*dtc));
+        self.snapshots.retain(|(dtc, _), _| !selected(*dtc));
+        Ok(Vec::new())
+    }
+
+    /// Parses the address and size encoded with `format`, which must make up
+    /// all of `data`, and returns the memory range they select.
+    fn parse_memory_range(
+        &self,
+        format: MemoryAddressFormat,
+        data: &[u8],
+    ) -> Result<(usize, usize), Nrc> {
+        if data.len() != address_and_length_size(format, data)? {
+            return Err(Nrc::IncorrectMessageLength);
+        }
+        let (address_len, offset) = if format.has_alfid() {
+            ((data[0] & 0x0F) as usize, 1)
+        } else {
+            (format.address_bytes() as usize, 0)
+        };
+        let address = read_be(&data[offset..offset + address_len]);
+        let size = read_be(&data[offset + address_len..]);
+        self.memory_range(address, size)
+    }
+
+    /// Starts a download, or an upload if `upload` is true.
+    fn request_transfer(&mut self, data: &[u8], upload: bool) -> Result<Vec<u8>, Nrc> {
+        if data.is_empty() {
+            return Err(Nrc::IncorrectMessageLength);
+        }
+        self.require_security()?;
+        if self.transfer.is_some() {
+            return Err(Nrc::RequestSequenceError);
+        }
+        // Only uncompressed, unencrypted data is supported
+        if data[0] != 0 {
+            return Err(Nrc::RequestOutOfRange);
+        }
+        let (start, end) = self.parse_memory_range(self.download_address_format, &data[1..])?;
+        self.transfer = Some(Transfer {
+            upload,
+            offset: start,
+            remaining: end - start,
+            sequence: 1,
+        });
+        let mut response = vec![0x20];
+        response.extend_from_slice(&self.max_block_length.to_be_bytes());
+        Ok(response)
+    }
+
+    fn transfer_data(&mut self, data: &[u8]) -> Result<Vec<u8>, Nrc> {
+        let sequence = *data.first().ok_or(Nrc::IncorrectMessageLength)?;
+        let block = &data[1..];
+        if block.len() + 2 > self.max_block_length as usize {
+            return Err(Nrc::IncorrectMessageLength);
+        }
+        let transfer = self.transfer.as_mut().ok_or(Nrc::RequestSequenceError)?;
+        if sequence != transfer.sequence {
+            return Err(Nrc::WrongBlockSequenceCounter);
+        }
+        if transfer.upload {
+            if !block.is_empty() {
+                return Err(Nrc::IncorrectMessageLength);
+            }
+            let len = cmp::min(transfer.remaining, self.max_block_length as usize - 2);
+            if len == 0 {
+                return Err(Nrc::RequestSequenceError);
+            }
+            let mut response = vec![sequence];
+            response.extend_from_slice(&self.rom.data()[transfer.offset..transfer.offset + len]);
+            transfer.offset += len;
+            transfer.remaining -= len;
+            transfer.sequence = transfer.sequence.wrapping_add(1);
+            return Ok(response);
+        }
+        if block.len() > transfer.remaining {
+            return Err(Nrc::TransferDataSuspended);
+        }
+
+        let range = transfer.offset..transfer.offset + block.len();
+        transfer.offset += block.len();
+        transfer.remaining -= block.len();
+        transfer.sequence = transfer.sequence.wrapping_add(1);
+        self.rom.data[range.clone()].copy_from_slice(block);
+        self.rom.mark_dirty(range);
+        Ok(vec![sequence])
+    }
+
+    fn transfer_exit(&mut self) -> Result<Vec<u8>, Nrc> {
+        match self.transfer.take() {
+            Some(transfer) if transfer.remaining == 0 => Ok(Vec::new()),
+            Some(_) => Err(Nrc::TransferDataSuspended),
+            None => Err(Nrc::RequestSequenceError),
+        }
+    }
+
+    fn routine_control(&mut self, data: &[u8]) -> Result<Vec<u8>, Nrc> {
+        if data.len() < 3 {
+            return Err(Nrc::IncorrectMessageLength);
+        }
+        // Only startRoutine eraseMemory is supported
+        if data[0] != 0x01 {
+            return Err(Nrc::SubFunctionNotSupported);
+        }
+        if data[1..3] != [0xFF, 0x00] {
+            return Err(Nrc::RequestOutOfRange);
+        }
+        self.require_security()?;
+        let (start, end) = self.parse_memory_range(self.download_address_format, &data[3..])?;
+        for b in &mut self.rom.data[start..end] {
+            *b = 0xFF;
+        }
+        self.rom.mark_dirty(start..end);
+        Ok(data[..3].to_vec())
+    }
+}
+
+/// Returns true if the first request byte of service `sid` is a sub-function
+/// with a suppressPosRspMsgIndicationBit. DiagnosticSessionControl is
+/// excluded because Mazda ECUs use session types with bit 7 set.
+fn has_sub_function(sid: u8) -> bool {
+    matches!(
+        sid,
+        UDS_REQ_ECURESET
+            | UDS_REQ_COMMUNICATIONCONTROL
+            | UDS_REQ_CONTROLDTCSETTING
+            | UDS_REQ_ROUTINECONTROL
+            | UDS_REQ_TESTERPRESENT
+    )
+}
+
+/// Returns the number of bytes taken by the addressAndLengthFormatIdentifier,
+/// address and size at the start of `data`. Formats with an identifier take
+/// the widths from `data`, others from `format`.
+fn address_and_length_size(format: MemoryAddressFormat, data: &[u8]) -> Result<usize, Nrc> {
+    let size = if format.has_alfid() {
+        let alfid = *data.first().ok_or(Nrc::IncorrectMessageLength)?;
+        let address_len = (alfid & 0x0F) as usize;
+        let size_len = (alfid >> 4) as usize;
+        if address_len == 0 || address_len > 8 || size_len == 0 || size_len > 8 {
+            return Err(Nrc::RequestOutOfRange);
+        }
+        1 + address_len + size_len
+    } else {
+        format.address_bytes() as usize + format.size_bytes() as usize
+    };
+    if data.len() < size {
+        return Err(Nrc::IncorrectMessageLength);
+    }
+    Ok(size)
+}
+
+/// Reads a big-endian integer of up to 8 bytes.
+fn read_be(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |n, &b| (n << 8) | b as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::ops::Range;
+    use std::sync::Arc;
+
+    use crate::datalink::can::VirtualBus;
+    use crate::datalink::dtc::DtcStatus;
+    use crate::datalink::isotp::IsotpCan;
+    use crate::datalink::security::MazdaAlgorithm;
+    use crate::datalink::uds::{
+        MemoryAddressFormat, ResetType, UdsError, UdsInterface, UDS_COMMUNICATION_ALL,
+    };
+
+    use super::*;
+
+    /// Sends requests directly to the ECU without a transport.
+    impl UdsInterface for RefCell<SimulatedEcu> {
+        fn request(&self, request_sid: u8, data: &[u8]) -> Result<Vec<u8>, UdsError> {
+            let mut request = vec![request_sid];
+            request.extend_from_slice(data);
+            let response = self.borrow_mut().handle(&request);
+            if response[0] == 0x7F {
+                return Err(UdsError::NegativeResponse {
+                    sid: request_sid,
+                    code: Nrc::from(response[2]),
+                });
+            }
+            assert_eq!(response[0], request_sid + 0x40);
+            Ok(response[1..].to_vec())
+        }
+    }
+
+    fn ecu() -> SimulatedEcu {
+        let rom = Rom::from((0..64).collect::<Vec<u8>>());
+        let mut ecu = SimulatedEcu::new(rom, Box::new(MazdaAlgorithm::default()));
+        ecu.set_base_address(0x1000);
+        ecu
+    }
+
+    fn unlock(ecu: &mut SimulatedEcu) {
+        assert_eq!(
+            ecu.handle(&[0x27, 0x01]),
+            vec![0x67, 0x01, 0x12, 0x34, 0x56]
+        );
+        assert_eq!(
+            ecu.handle(&[0x27, 0x02, 0x86, 0xCA, 0x06]),
+            vec![0x67, 0x02]
+        );
+    }
+
+    #[test]
+    fn session_and_security() {
+        let mut ecu = ecu();
+        assert_eq!(
+            ecu.handle(&[0x10, 0x85]),
+            vec![0x50, 0x85, 0x00, 0x32, 0x01, 0xF4]
+        );
+        assert_eq!(ecu.session(), 0x85);
+
+        // Key without seed
+        assert_eq!(
+            ecu.handle(&[0x27, 0x02, 0x86, 0xCA, 0x06]),
+            vec![0x7F, 0x27, 0x24]
+        );
+        // Wrong key
+        ecu.handle(&[0x27, 0x01]);
+        assert_eq!(
+            ecu.handle(&[0x27, 0x02, 0x00, 0x00, 0x00]),
+            vec![0x7F, 0x27, 0x35]
+        );
+
+        unlock(&mut ecu);
+        // Unlocked levels send a zero seed
+        assert_eq!(
+            ecu.handle(&[0x27, 0x01]),
+            vec![0x67, 0x01, 0x00, 0x00, 0x00]
+        );
+
+        // Changing session locks the ECU
+        ecu.handle(&[0x10, 0x01]);
+        assert_eq!(
+            ecu.handle(&[0x23, 0x24, 0x00, 0x00, 0x10, 0x00, 0x00, 0x01]),
+            vec![0x7F, 0x23, 0x33]
+        );
+    }
+
+    #[test]
+    fn read_memory_and_data() {
+        let mut ecu = ecu();
+        ecu.set_data_identifier(0xF190, b"VIN".to_vec());
+        assert_eq!(
+            ecu.handle(&[0x22, 0xF1, 0x90]),
+            vec![0x62, 0xF1, 0x90, b'V', b'I', b'N']
+        );
+        assert_eq!(ecu.handle(&[0x22, 0xF1, 0x91]), vec![0x7F, 0x22, 0x31]);
+
+        let read = [0x23, 0x12, 0x10, 0x3E, 0x02];
+        assert_eq!(ecu.handle(&read), vec![0x7F, 0x23, 0x33]);
+        unlock(&mut ecu);
+        assert_eq!(ecu.handle(&read), vec![0x63, 62, 63]);
+        assert_eq!(
+            ecu.handle(&[0x23, 0x12, 0x10, 0x3F, 0x02]),
+            vec![0x7F, 0x23, 0x31]
+        );
+        assert_eq!(
+            ecu.handle(&[0x23, 0x12, 0x0F, 0xFF, 0x01]),
+            vec![0x7F, 0x23, 0x31]
+        );
+    }
+
+    #[test]
+    fn download() {
+        let mut ecu = ecu();
+        let request = [
+            0x34, 0x00, 0x44, 0x00, 0x00, 0x10, 0x08, 0x00, 0x00, 0x00, 0x04,
+        ];
+        assert_eq!(ecu.handle(&request), vec![0x7F, 0x34, 0x33]);
+        unlock(&mut ecu);
+
+        assert_eq!(
+            ecu.handle(&[
+                0x31, 0x01, 0xFF, 0x00, 0x44, 0x00, 0x00, 0x10, 0x08, 0x00, 0x00, 0x00, 0x08
+            ]),
+            vec![0x71, 0x01, 0xFF, 0x00]
+        );
+        assert_eq!(&ecu.rom().data()[8..16], &[0xFF; 8]);
+
+        assert_eq!(ecu.handle(&request), vec![0x74, 0x20, 0x08, 0x02]);
+        assert_eq!(ecu.handle(&[0x36, 0x02, 0xAA]), vec![0x7F, 0x36, 0x73]);
+        assert_eq!(ecu.handle(&[0x36, 0x01, 0xAA, 0xBB]), vec![0x76, 0x01]);
+        // Exit before all data is transferred
+        assert_eq!(ecu.handle(&[0x37]), vec![0x7F, 0x37, 0x71]);
+
+        assert_eq!(ecu.handle(&request), vec![0x74, 0x20, 0x08, 0x02]);
+        assert_eq!(ecu.handle(&[0x36, 0x01, 1, 2]), vec![0x76, 0x01]);
+        assert_eq!(ecu.handle(&[0x36, 0x02, 3, 4, 5]), vec![0x7F, 0x36, 0x71]);
+        assert_eq!(ecu.handle(&[0x36, 0x02, 3, 4]), vec![0x76, 0x02]);
+        assert_eq!(ecu.handle(&[0x37]), vec![0x77]);
+        assert_eq!(
+            &ecu.rom().data()[8..16],
+            &[1, 2, 3, 4, 0xFF, 0xFF, 0xFF, 0xFF]
+        );
+        assert_eq!(ecu.rom().dirty_regions(), &[Range { start: 8, end: 16 }]);
+    }
+
+    #[test]
+    fn tester_present() {
+        let mut ecu = ecu();
+        ecu.set_s3_timeout(Duration::from_millis(50));
+        assert_eq!(ecu.handle(&[0x3E, 0x00]), vec![0x7E, 0x00]);
+        assert_eq!(ecu.handle(&[0x3E, 0x80]), vec![]);
+        assert_eq!(ecu.handle(&[0x3E, 0x01]), vec![0x7F, 0x3E, 0x12]);
+
+        ecu.handle(&[0x10, 0x87]);
+        thread::sleep(Duration::from_millis(30));
+        ecu.handle(&[0x3E, 0x80]);
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(
+            ecu.handle(&[0x22, 0xF1, 0x86]),
+            vec![0x62, 0xF1, 0x86, 0x87]
+        );
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(
+            ecu.handle(&[0x22, 0xF1, 0x86]),
+            vec![0x62, 0xF1, 0x86, 0x01]
+        );
+    }
+
+    #[test]
+    fn write() {
+        let ecu = RefCell::new(ecu());
+        ecu.borrow_mut().set_data_identifier(0x0100, vec![0x00]);
+        ecu.write_data_by_identifier(0x0100, &[0x12, 0x34]).unwrap();
+        assert_eq!(
+            ecu.read_data_by_identifier(0x0100).unwrap(),
+            vec![0x12, 0x34]
+        );
+        assert!(ecu.write_data_by_identifier(0x0101, &[0x12]).is_err());
+
+        let format = MemoryAddressFormat::new(2, 1).unwrap();
+
+        assert!(matches!(
+            ecu.write_memory_by_address(format, 0x1004, &[0xAA, 0xBB]),
+            Err(UdsError::NegativeResponse {
+                code: Nrc::SecurityAccessDenied,
+                ..
+            })
+        ));
+        ecu.unlock(1, &MazdaAlgorithm::default()).unwrap();
+        ecu.write_memory_by_address(format, 0x1004, &[0xAA, 0xBB])
+            .unwrap();
+        assert_eq!(&ecu.borrow().rom().data()[3..7], &[3, 0xAA, 0xBB, 6]);
+        assert_eq!(
+            ecu.borrow().rom().dirty_regions(),
+            &[Range { start: 4, end: 6 }]
+        );
+        assert!(ecu
+            .write_memory_by_address(format, 0x103F, &[0xAA, 0xBB])
+            .is_err());
+    }
+
+    #[test]
+    fn upload() {
+        let ecu = RefCell::new(ecu());
+        ecu.borrow_mut().set_max_block_length(0x12);
+        ecu.unlock(1, &MazdaAlgorithm::default()).unwrap();
+        let format = MemoryAddressFormat::new(2, 1).unwrap();
+        assert_eq!(ecu.request_upload(0, format, 0x1008, 0x20).unwrap(), 0x12);
+        let mut data = Vec::new();
+        for sequence in 1..=2 {
+            data.extend(ecu.transfer_data(sequence, &[]).unwrap());
+        }
+        assert!(ecu.request_transfer_exit().is_ok());
+        assert_eq!(data, (8..0x28).collect::<Vec<u8>>());
+        assert_eq!(
+            ecu.request_read_memory_address(format, 0x1000, 4).unwrap(),
+            vec![0, 1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn reset_and_communication_control() {
+        let ecu = RefCell::new(ecu());
+        assert!(matches!(
+            ecu.control_dtc_setting(DtcSettingType::Off),
+            Err(UdsError::NegativeResponse {
+                code: Nrc::ServiceNotSupportedInActiveSession,
+                ..
+            })
+        ));
+        // Rejected requests do not change the communication state
+        assert!(ecu
+            .communication_control(
+                CommunicationControlType::DisableRxAndTx,
+                UDS_COMMUNICATION_ALL
+            )
+            .is_err());
+        assert_eq!(
+            ecu.borrow().communication(),
+            CommunicationControlType::EnableRxAndTx
+        );
+
+        ecu.request_session(0x03).unwrap();
+        ecu.control_dtc_setting(DtcSettingType::Off).unwrap();
+        ecu.communication_control(
+            CommunicationControlType::EnableRxAndDisableTx,
+            UDS_COMMUNICATION_ALL,
+        )
+        .unwrap();
+        assert_eq!(ecu.borrow().dtc_setting(), DtcSettingType::Off);
+        assert_eq!(
+            ecu.borrow().communication(),
+            CommunicationControlType::EnableRxAndDisableTx
+        );
+
+        assert_eq!(
+            ecu.ecu_reset(ResetType::EnableRapidPowerShutDown).unwrap(),
+            Some(0x0F)
+        );
+        assert_eq!(ecu.ecu_reset(ResetType::HardReset).unwrap(), None);
+        assert_eq!(ecu.borrow().resets(), 1);
+        assert_eq!(ecu.borrow().session(), DEFAULT_SESSION);
+        assert_eq!(ecu.borrow().dtc_setting(), DtcSettingType::On);
+        assert_eq!(
+            ecu.borrow().communication(),
+            CommunicationControlType::EnableRxAndTx
+        );
+
+        // Suppressed positive response
+        assert_eq!(ecu.borrow_mut().handle(&[0x11, 0x81]), vec![]);
+        assert_eq!(ecu.borrow().resets(), 2);
+    }
+
+    #[test]
+    fn dtcs() {
+        let ecu = RefCell::new(ecu());
+        ecu.borrow_mut().set_dtc(0x030100, 0x2F);
+        ecu.borrow_mut().set_dtc(0x011300, 0x24);
+        ecu.borrow_mut().set_dtc(0xC10087, 0x08);
+        ecu.borrow_mut().set_dtc_snapshot(
+            0x030100,
+            0x01,
+            vec![(0xF40C, vec![0x0B, 0xB8]), (0xF405, vec![0x5A])],
+        );
+
+        assert_eq!(ecu.read_dtc_count(0xFF).unwrap(), 3);
+        assert_eq!(ecu.read_dtc_count(0x08).unwrap(), 2);
+        let dtcs = ecu.read_dtcs(0x04).unwrap();
+        assert_eq!(
+            dtcs.iter().map(|dtc| dtc.to_string()).collect::<Vec<_>>(),
+            vec!["P0301-00", "P0113-00"]
+        );
+        assert_eq!(
+            dtcs[1].status,
+            DtcStatus {
+                pending: true,
+                test_failed_since_last_clear: true,
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(ecu.read_dtc_snapshot_ids().unwrap(), vec![(0x030100, 0x01)]);
+        let snapshot = ecu.read_dtc_snapshot(0x030100, 0x01).unwrap().unwrap();
+        assert_eq!(snapshot.dtc, dtcs[0]);
+        assert_eq!(snapshot.identifiers, 2);
+        assert_eq!(
+            snapshot.data,
+            vec![0xF4, 0x0C, 0x0B, 0xB8, 0xF4, 0x05, 0x5A]
+        );
+        assert_eq!(ecu.read_dtc_snapshot(0x011300, 0x01).unwrap(), None);
+        assert!(matches!(
+            ecu.read_dtc_snapshot(0x123456, 0x01),
+            Err(UdsError::NegativeResponse {
+                code: Nrc::RequestOutOfRange,
+                ..
+            })
+        ));
+        assert!(matches!(
+            ecu.read_dtc_snapshot(0x030100, 0xFF),
+            Err(UdsError::UnsupportedRecordNumber(0xFF))
+        ));
+        assert!(matches!(
+            ecu.read_dtc_snapshot(0x0100_0000, 0x01),
+            Err(UdsError::InvalidDtc(0x0100_0000))
+        ));
+        assert!(matches!(
+            ecu.clear_dtcs(0x0100_0000),
+            Err(UdsError::InvalidDtc(0x0100_0000))
+        ));
+
+        ecu.clear_dtcs(0x030100).unwrap();
+        assert_eq!(ecu.read_dtc_count(0xFF).unwrap(), 2);
+        assert!(ecu.read_dtc_snapshot_ids().unwrap().is_empty());
+        ecu.clear_dtcs(DTC_GROUP_ALL).unwrap();
+        assert!(ecu.read_dtcs(0xFF).unwrap().is_empty());
+    }
+
+    #[test]
+    fn negative_response_override() {
+        let mut ecu = ecu();
+        assert_eq!(ecu.handle(&[0x2F, 0x00]), vec![0x7F, 0x2F, 0x11]);
+        ecu.set_negative_response(0x10, Some(Nrc::ConditionsNotCorrect));
+        assert_eq!(ecu.handle(&[0x10, 0x85]), vec![0x7F, 0x10, 0x22]);
+        ecu.set_negative_response(0x10, None);
+        assert_eq!(ecu.handle(&[0x10, 0x85])[0], 0x50);
+    }
+
+    #[test]
+    fn memory_address_format() {
+        let mut ecu = ecu();
+        ecu.set_memory_address_format(MemoryAddressFormat::without_alfid(2, 1).unwrap());
+        unlock(&mut ecu);
+        assert_eq!(ecu.handle(&[0x23, 0x10, 0x3E, 0x02]), vec![0x63, 62, 63]);
+        // Requests with an ALFID are too long
+        assert_eq!(
+            ecu.handle(&[0x23, 0x12, 0x10, 0x3E, 0x02]),
+            vec![0x7F, 0x23, 0x13]
+        );
+        assert_eq!(
+            ecu.handle(&[0x3D, 0x10, 0x00, 0x01, 0xAA]),
+            vec![0x7D, 0x10, 0x00, 0x01]
+        );
+        assert_eq!(ecu.rom().data()[0], 0xAA);
+    }
+
+    #[test]
+    fn mazdaspeed6() {
+        use crate::download::{download_rom, DownloadOptions};
+        use crate::flash::{flash_rom, FlashOptions};
+        use crate::platform::{Download, Mazdaspeed6, Platform};
+
+        let rom = (0..Mazdaspeed6.rom_length())
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<u8>>();
+        let mut ecu =
+            SimulatedEcu::new(Rom::from(rom.clone()), Box::new(MazdaAlgorithm::default()));
+        ecu.set_memory_address_format(Mazdaspeed6.memory_address_format());
+        ecu.set_download_address_format(Mazdaspeed6.download_address_format());
+        let ecu = RefCell::new(ecu);
+
+        let downloaded =
+            download_rom(&ecu, &Mazdaspeed6, &DownloadOptions::default(), |_, _| true).unwrap();
+        assert_eq!(downloaded.data(), &rom[..]);
+
+        let flashed = Rom::from(rom.iter().map(|b| !b).collect::<Vec<u8>>());
+        flash_rom(
+            &ecu,
+            &Mazdaspeed6,
+            &flashed,
+            &FlashOptions::default(),
+            |_| true,
+        )
+        .unwrap();
+        assert_eq!(ecu.borrow().rom().data(), flashed.data());
+    }
+
+    #[test]
+    fn serve_over_isotp() {
+        let bus = VirtualBus::new();
+        let server = IsotpCan::new(bus.endpoint(), 0x7E8, 0x7E0, Duration::from_millis(10));
+        let tester = IsotpCan::new(bus.endpoint(), 0x7E0, 0x7E8, Duration::from_secs(1));
+
+        let mut ecu = ecu();
+        ecu.set_response_pending(0x10, 2, Duration::from_millis(5));
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                ecu.serve(&server, &stop).unwrap();
+                ecu
+            })
+        };
+
+        let uds: &dyn Isotp = &tester;
+        assert_eq!(
+            uds.request_session(0x85).unwrap(),
+            vec![0x00, 0x32, 0x01, 0xF4]
+        );
+        uds.unlock(1, &MazdaAlgorithm::default()).unwrap();
+        assert!(matches!(
+            uds.request(0x2F, &[0x00]),
+            Err(UdsError::NegativeResponse {
+                sid: 0x2F,
+                code: Nrc::ServiceNotSupported,
+            })
+        ));
+
+        stop.store(true, Ordering::Relaxed);
+        let ecu = handle.join().unwrap();
+        assert_eq!(ecu.session(), 0x85);
+    }
+}
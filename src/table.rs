@@ -1,6 +1,4 @@
-use std::io::{Error, Read, Seek, SeekFrom, Write};
-
-use byteordered::{ByteOrdered, Endianness};
+use byteordered::Endianness;
 use num::cast::AsPrimitive;
 
 use crate::numvec::DataType;
@@ -17,16 +15,21 @@ pub enum AxisTicks {
 /// Table axis
 pub struct Axis {
     /// Unique identifier string
-    id: String,
+    pub id: String,
 
     /// Short axis name
-    name: String,
+    pub name: String,
 
     /// Long axis description
-    description: String,
+    pub description: String,
 
     /// Ticks
-    ticks: AxisTicks,
+    pub ticks: AxisTicks,
+
+    /// Data type of ticks stored in memory
+    pub data_type: DataType,
+
+    pub endianness: Endianness,
 }
 
 /// Interpolation used during table queries
@@ -66,18 +69,18 @@ pub struct Table {
 
 impl Table {
     /// Returns true if the table contains only one value.
-    fn is_scalar(&self) -> bool {
+    pub fn is_scalar(&self) -> bool {
         self.width == 1 && self.height == 1
     }
 
     /// Returns true if the table height equals 1.
     /// Note: scalar values will return true.
-    fn is_one_dimensional(&self) -> bool {
+    pub fn is_one_dimensional(&self) -> bool {
         self.height == 1
     }
 
     /// Returns the size of the table in bytes.
-    fn byte_size(&self) -> usize {
+    pub fn byte_size(&self) -> usize {
         self.data_type.byte_size() * self.width * self.height
     }
 
@@ -87,12 +90,12 @@ impl Table {
     }
 
     /// Returns table width
-    fn width(&self) -> usize {
+    pub fn width(&self) -> usize {
         self.width
     }
 
     /// Returns table height
-    fn height(&self) -> usize {
+    pub fn height(&self) -> usize {
         self.height
     }
 }
@@ -171,6 +174,27 @@ impl NumVec {
     pub fn len(&self) -> usize {
         expand_numvec!(self, v, v.len())
     }
+
+    /// Returns the data type of the vector elements
+    pub fn data_type(&self) -> DataType {
+        match self {
+            NumVec::I8(_) => DataType::I8,
+            NumVec::U8(_) => DataType::U8,
+            NumVec::I16(_) => DataType::I16,
+            NumVec::U16(_) => DataType::U16,
+            NumVec::I32(_) => DataType::I32,
+            NumVec::U32(_) => DataType::U32,
+            NumVec::I64(_) => DataType::I64,
+            NumVec::U64(_) => DataType::U64,
+            NumVec::F32(_) => DataType::F32,
+            NumVec::F64(_) => DataType::F64,
+        }
+    }
+
+    /// Returns true if the vector contains no elements
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 /// Container for two-dimensional table data
@@ -182,8 +206,34 @@ pub struct TableData {
 }
 
 impl TableData {
+    /// Creates table data from a row-major vector. The length of `data` must
+    /// equal `width * height`.
+    pub fn new(data: NumVec, width: usize, height: usize) -> TableData {
+        assert_eq!(data.len(), width * height);
+        TableData {
+            data,
+            width,
+            height,
+        }
+    }
+
+    /// Returns the row-major table data
+    pub fn data(&self) -> &NumVec {
+        &self.data
+    }
+
+    /// Returns table width
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Returns table height
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
     /// Returns entry at (col, row) from table casted to type.
-    fn get<T>(&self, col: usize, row: usize) -> T
+    pub fn get<T>(&self, col: usize, row: usize) -> T
         where
             T: Copy + 'static,
             i8: AsPrimitive<T>,
@@ -218,8 +268,12 @@ impl TableData {
     }
 }
 
+#[cfg(test)]
 mod tests {
-    use std::io::{Cursor, Seek};
+    use std::io::Cursor;
+    use std::ops::Range;
+
+    use crate::RomRead;
 
     use super::*;
 
@@ -258,19 +312,51 @@ mod tests {
 
         let mut wr = byteordered::ByteOrdered::be(&mut buff);
         for i in 0..64 {
-            wr.write_i32(i);
+            wr.write_i32(i).unwrap();
         }
         // Seek to beginning of buffer
         buff.set_position(0);
-        /*
-        let table_data = buff.read_table(&table).unwrap();
+
+        let rom = buff.read_rom(64 * 4).unwrap();
+        let table_data = rom.read_table(&table).unwrap();
         for r in 0..8_i32 {
             for c in 0..8_i32 {
                 assert_eq!(table_data.get::<i32>(c as usize, r as usize), r * 8 + c);
             }
-        }*/
+        }
     }
 
     #[test]
-    fn table_write() {}
+    fn table_write() {
+        let table = Table {
+            width: 4,
+            height: 2,
+            offset: 4,
+            name: "".to_string(),
+            description: "".to_string(),
+            id: "".to_string(),
+            x_axis_id: None,
+            y_axis_id: None,
+            interpolation: Interpolation::Linear,
+            data_type: DataType::F32,
+            endianness: Endianness::Little,
+        };
+
+        let mut rom = Cursor::new(vec![0_u8; 64]).read_rom(64).unwrap();
+        let mut table_data = rom.read_table(&table).unwrap();
+        for r in 0..2 {
+            for c in 0..4 {
+                table_data.set(c, r, (r * 4 + c) as f32 * 0.5);
+            }
+        }
+        rom.write_table(&table, &table_data).unwrap();
+        assert_eq!(rom.dirty_regions(), &[Range { start: 4, end: 36 }]);
+
+        let table_data = rom.read_table(&table).unwrap();
+        for r in 0..2 {
+            for c in 0..4 {
+                assert_eq!(table_data.get::<f32>(c, r), (r * 4 + c) as f32 * 0.5);
+            }
+        }
+    }
 }